@@ -0,0 +1,122 @@
+use std::char;
+
+use chip8::types::ByteVal;
+use chip8::display_buffer::Frame;
+use backends::palette::Palette;
+
+// Quadrant block elements, indexed by the lit quarters: top left, top
+// right, bottom left then bottom right
+const QUADRANTS: [char; 16] = [
+    ' ', '\u{2598}', '\u{259d}', '\u{2580}', '\u{2596}', '\u{258c}', '\u{259e}', '\u{259b}',
+    '\u{2597}', '\u{259a}', '\u{2590}', '\u{259c}', '\u{2584}', '\u{2599}', '\u{259f}', '\u{2588}'
+];
+
+// Braille dot for each pixel of a 2x4 cell, left to right then top to
+// bottom. Dots 7 and 8 were added below the original six.
+const BRAILLE_DOTS: [u32; 8] = [0x01, 0x08, 0x02, 0x10, 0x04, 0x20, 0x40, 0x80];
+
+// Sextants are numbered like quadrants, two to a row. Unicode leaves out
+// the ones that already exist as blocks: empty, left half, right half and
+// full.
+fn sextant(bits: u32) -> char {
+    match bits {
+        0  => ' ',
+        21 => '\u{258c}',
+        42 => '\u{2590}',
+        63 => '\u{2588}',
+        _  => {
+            let skipped = if bits > 42 { 2 } else if bits > 21 { 1 } else { 0 };
+            char::from_u32(0x1fb00 + bits - 1 - skipped).unwrap_or(' ')
+        }
+    }
+}
+
+// How display pixels are packed into character cells
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CellMode {
+    // 1x2 pixels per cell, each in its own colour. Pixels come out roughly
+    // square.
+    HalfBlock,
+    // 2x2
+    Quadrant,
+    // 2x3, which needs a font with the Symbols for Legacy Computing
+    Sextant,
+    // 2x4, as Braille dots
+    Braille
+}
+
+// Fewest pixels per cell first, the order automatic selection tries them in
+const MODES: [CellMode; 4] = [CellMode::HalfBlock, CellMode::Quadrant, CellMode::Sextant, CellMode::Braille];
+
+impl CellMode {
+    // None stands for picking a mode to fit the terminal
+    pub fn from_name(name: &str) -> Option<Option<CellMode>> {
+        match name {
+            "auto"     => Some(None),
+            "half"     => Some(Some(CellMode::HalfBlock)),
+            "quadrant" => Some(Some(CellMode::Quadrant)),
+            "sextant"  => Some(Some(CellMode::Sextant)),
+            "braille"  => Some(Some(CellMode::Braille)),
+            _          => None
+        }
+    }
+
+    // Pixels per cell across and down
+    pub fn cell_size(&self) -> (usize, usize) {
+        match *self {
+            CellMode::HalfBlock => (1, 2),
+            CellMode::Quadrant  => (2, 2),
+            CellMode::Sextant   => (2, 3),
+            CellMode::Braille   => (2, 4)
+        }
+    }
+
+    // Cells needed for a display of the given size
+    pub fn cells(&self, size: (usize, usize)) -> (usize, usize) {
+        let (cell_width, cell_height) = self.cell_size();
+        ((size.0 + cell_width - 1) / cell_width, (size.1 + cell_height - 1) / cell_height)
+    }
+
+    // The mode with the fewest pixels per cell that fits in the space
+    // available, or the one with the most when none does
+    pub fn fitting(size: (usize, usize), space: (usize, usize)) -> CellMode {
+        MODES.iter().cloned()
+            .find(|mode| {
+                let (columns, rows) = mode.cells(size);
+                columns <= space.0 && rows <= space.1
+            })
+            .unwrap_or(CellMode::Braille)
+    }
+
+    // The glyph for a cell and its foreground and background colours
+    pub fn cell(&self, frame: &Frame, palette: &Palette, column: usize, row: usize) -> (char, u32, u32) {
+        let (cell_width, cell_height) = self.cell_size();
+        let (x, y) = (column * cell_width, row * cell_height);
+        if *self == CellMode::HalfBlock {
+            return ('\u{2580}', palette.colour(frame.pixel(x, y)), palette.colour(frame.pixel(x, y + 1)));
+        }
+
+        // The other modes have more pixels than colours, so every lit pixel
+        // is drawn in the colour most of them share
+        let mut bits = 0;
+        let mut counts = [0; 4];
+        for i in 0..cell_width * cell_height {
+            let pixel = frame.pixel(x + i % cell_width, y + i / cell_width);
+            if pixel != 0 {
+                bits |= 1 << i;
+                counts[pixel as usize & 3] += 1;
+            }
+        }
+        let lit = (1..4).fold(1, |best, pixel| if counts[pixel] > counts[best] { pixel } else { best });
+
+        let glyph = match *self {
+            CellMode::Quadrant => QUADRANTS[bits as usize],
+            CellMode::Sextant  => sextant(bits),
+            _                  => {
+                let dots = (0..8).filter(|i| bits & (1 << i) != 0).fold(0, |dots, i| dots | BRAILLE_DOTS[i]);
+                if dots == 0 { ' ' } else { char::from_u32(0x2800 + dots).unwrap_or(' ') }
+            }
+        };
+        (glyph, palette.colour(lit as ByteVal), palette.colour(0))
+    }
+}
@@ -0,0 +1,189 @@
+use config::VideoConfig;
+
+// Frames are upscaled to about this width before the effects are applied,
+// so each display pixel covers several texels
+const CRT_WIDTH: usize = 512;
+const MIN_SCALE: usize = 2;
+const MAX_SCALE: usize = 16;
+
+// Effects used when the hotkey turns them on without any configured
+const DEFAULT_EFFECTS: [&'static str; 3] = ["scanlines", "glow", "vignette"];
+
+const SCANLINE_LEVEL: f32 = 0.55;
+const GRID_LEVEL: f32 = 0.75;
+const GLOW_LEVEL: f32 = 0.45;
+const CURVATURE: f32 = 0.06;
+const VIGNETTE: f32 = 0.35;
+
+fn scale_colour(colour: u32, level: f32) -> u32 {
+    let channel = |shift: u32| ((((colour >> shift) & 0xff) as f32 * level) as u32).min(0xff) << shift;
+    0xff00_0000 | channel(16) | channel(8) | channel(0)
+}
+
+fn add_colours(a: u32, b: u32) -> u32 {
+    let channel = |shift: u32| (((a >> shift) & 0xff) + ((b >> shift) & 0xff)).min(0xff) << shift;
+    0xff00_0000 | channel(16) | channel(8) | channel(0)
+}
+
+// Retro post-processing done on the CPU, on an upscaled copy of the frame
+#[derive(Clone, Debug)]
+pub struct CrtEffects {
+    enabled:   bool,
+    scanlines: bool,
+    grid:      bool,
+    glow:      bool,
+    curvature: bool,
+    vignette:  bool,
+    scale:     Option<usize>
+}
+
+impl CrtEffects {
+    pub fn from_config(video: &VideoConfig) -> Self {
+        let names: Vec<String> = match video.crt {
+            Some(ref names) => names.clone(),
+            None            => DEFAULT_EFFECTS.iter().map(|name| name.to_string()).collect()
+        };
+        let mut effects = CrtEffects {
+            enabled:   video.crt.as_ref().map_or(false, |names| !names.is_empty()),
+            scanlines: false,
+            grid:      false,
+            glow:      false,
+            curvature: false,
+            vignette:  false,
+            scale:     video.crt_scale.map(|scale| scale.max(1).min(MAX_SCALE))
+        };
+        for name in names.iter() {
+            match name.as_str() {
+                "scanlines" => effects.scanlines = true,
+                "grid"      => effects.grid = true,
+                "glow"      => effects.glow = true,
+                "curvature" => effects.curvature = true,
+                "vignette"  => effects.vignette = true,
+                _           => eprintln!("Unknown CRT effect {}", name)
+            }
+        }
+        effects
+    }
+
+    pub fn toggle(&mut self) -> bool {
+        self.enabled = !self.enabled;
+        self.enabled
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn scale_for(&self, width: usize) -> usize {
+        self.scale.unwrap_or((CRT_WIDTH / width.max(1)).max(MIN_SCALE).min(MAX_SCALE))
+    }
+
+    // Returns the processed frame and its size
+    pub fn apply(&self, frame: &[u32], size: (usize, usize)) -> (Vec<u32>, (usize, usize)) {
+        let scale = self.scale_for(size.0);
+        let (width, height) = (size.0 * scale, size.1 * scale);
+
+        let mut out = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let row = &frame[(y / scale) * size.0..(y / scale + 1) * size.0];
+            for x in 0..width {
+                out.push(row[x / scale]);
+            }
+        }
+
+        if self.glow {
+            out = CrtEffects::glow(&out, width, height, scale);
+        }
+        if self.scanlines || self.grid {
+            self.darken_lines(&mut out, width, height, scale);
+        }
+        if self.curvature {
+            out = CrtEffects::curve(&out, width, height);
+        }
+        if self.vignette {
+            CrtEffects::vignette(&mut out, width, height);
+        }
+        (out, (width, height))
+    }
+
+    // Add a blurred copy of the frame, so lit pixels bleed into their
+    // neighbours
+    fn glow(frame: &[u32], width: usize, height: usize, scale: usize) -> Vec<u32> {
+        let radius = scale as isize;
+        let blur = |source: &[u32], dx: isize, dy: isize| -> Vec<u32> {
+            let mut blurred = Vec::with_capacity(source.len());
+            let count = (radius * 2 + 1) as u32;
+            for y in 0..height as isize {
+                for x in 0..width as isize {
+                    let (mut r, mut g, mut b) = (0, 0, 0);
+                    for i in -radius..radius + 1 {
+                        let (sx, sy) = (x + i * dx, y + i * dy);
+                        if sx >= 0 && sx < width as isize && sy >= 0 && sy < height as isize {
+                            let pixel = source[sy as usize * width + sx as usize];
+                            r += (pixel >> 16) & 0xff;
+                            g += (pixel >> 8) & 0xff;
+                            b += pixel & 0xff;
+                        }
+                    }
+                    blurred.push(((r / count) << 16) | ((g / count) << 8) | (b / count));
+                }
+            }
+            blurred
+        };
+
+        let blurred = blur(&blur(frame, 1, 0), 0, 1);
+        frame.iter().zip(blurred.iter())
+             .map(|(&pixel, &glow)| add_colours(pixel, scale_colour(glow, GLOW_LEVEL)))
+             .collect()
+    }
+
+    // Scanlines darken the bottom of each row of display pixels, and the
+    // grid also darkens the right edge of each column
+    fn darken_lines(&self, frame: &mut [u32], width: usize, height: usize, scale: usize) {
+        let line = (scale / 3).max(1);
+        for y in 0..height {
+            let scanline = y % scale >= scale - line;
+            for x in 0..width {
+                let level = if self.scanlines && scanline {
+                    SCANLINE_LEVEL
+                } else if self.grid && (scanline || x % scale >= scale - line) {
+                    GRID_LEVEL
+                } else {
+                    continue;
+                };
+                frame[y * width + x] = scale_colour(frame[y * width + x], level);
+            }
+        }
+    }
+
+    // Barrel distortion, with black outside the curved screen
+    fn curve(frame: &[u32], width: usize, height: usize) -> Vec<u32> {
+        let mut curved = Vec::with_capacity(frame.len());
+        for y in 0..height {
+            let v = y as f32 / height as f32 * 2.0 - 1.0;
+            for x in 0..width {
+                let u = x as f32 / width as f32 * 2.0 - 1.0;
+                let (su, sv) = (u * (1.0 + CURVATURE * v * v), v * (1.0 + CURVATURE * u * u));
+                let (sx, sy) = (((su + 1.0) / 2.0 * width as f32) as isize, ((sv + 1.0) / 2.0 * height as f32) as isize);
+                curved.push(if sx >= 0 && sx < width as isize && sy >= 0 && sy < height as isize {
+                    frame[sy as usize * width + sx as usize]
+                } else {
+                    0xff00_0000
+                });
+            }
+        }
+        curved
+    }
+
+    // Darken towards the corners
+    fn vignette(frame: &mut [u32], width: usize, height: usize) {
+        for y in 0..height {
+            let v = y as f32 / height as f32 * 2.0 - 1.0;
+            for x in 0..width {
+                let u = x as f32 / width as f32 * 2.0 - 1.0;
+                let level = (1.0 - VIGNETTE * (u * u + v * v) / 2.0).max(0.0);
+                frame[y * width + x] = scale_colour(frame[y * width + x], level);
+            }
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use sdl2::GameControllerSubsystem;
+use sdl2::controller::{Axis, Button, GameController};
+
+use chip8::types::ByteVal;
+use chip8::romdb::GameKeys;
+use config::GamepadConfig;
+
+pub const MAX_PLAYERS: usize = 2;
+
+// Stick positions closer to the centre than this are ignored
+pub const DEFAULT_DEADZONE: i16 = 8000;
+
+// Common keypad keys for movement and fire, used when neither the config
+// nor the ROM database say otherwise
+const DEFAULT_UP: ByteVal = 0x2;
+const DEFAULT_DOWN: ByteVal = 0x8;
+const DEFAULT_LEFT: ByteVal = 0x4;
+const DEFAULT_RIGHT: ByteVal = 0x6;
+const DEFAULT_FIRE: ByteVal = 0x5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum GamepadInput {
+    Button(Button),
+    AxisNegative(Axis),
+    AxisPositive(Axis)
+}
+
+impl GamepadInput {
+    // Buttons use SDL controller mapping names such as "a" or "dpup", and
+    // stick directions the axis name followed by + or -, such as "leftx-"
+    pub fn from_name(name: &str) -> Option<GamepadInput> {
+        if name.ends_with('-') {
+            Axis::from_string(&name[..name.len() - 1]).map(GamepadInput::AxisNegative)
+        } else if name.ends_with('+') {
+            Axis::from_string(&name[..name.len() - 1]).map(GamepadInput::AxisPositive)
+        } else {
+            Button::from_string(name).map(GamepadInput::Button)
+        }
+    }
+}
+
+struct Player {
+    controller: Option<GameController>,
+    bindings:   HashMap<GamepadInput, ByteVal>,
+    // Bindings came from the config, so the ROM database leaves them alone
+    configured: bool,
+    deadzone:   i16,
+    // Buttons held down and stick directions currently past the deadzone
+    held:       Vec<GamepadInput>
+}
+
+fn direction_bindings(up: Option<ByteVal>, down: Option<ByteVal>, left: Option<ByteVal>, right: Option<ByteVal>)
+                      -> HashMap<GamepadInput, ByteVal> {
+    let mut bindings = HashMap::new();
+    let directions = [(up, Button::DPadUp, GamepadInput::AxisNegative(Axis::LeftY)),
+                      (down, Button::DPadDown, GamepadInput::AxisPositive(Axis::LeftY)),
+                      (left, Button::DPadLeft, GamepadInput::AxisNegative(Axis::LeftX)),
+                      (right, Button::DPadRight, GamepadInput::AxisPositive(Axis::LeftX))];
+    for &(key, button, stick) in directions.iter() {
+        if let Some(key) = key {
+            bindings.insert(GamepadInput::Button(button), key);
+            bindings.insert(stick, key);
+        }
+    }
+    bindings
+}
+
+impl Player {
+    fn new(config: Option<&GamepadConfig>, index: usize) -> Self {
+        let deadzone = config.and_then(|c| c.deadzone).unwrap_or(DEFAULT_DEADZONE);
+        let (bindings, configured) = match config {
+            Some(config) if !config.buttons.is_empty() => {
+                let bindings = config.buttons.iter().filter_map(|(name, key)| {
+                    match (GamepadInput::from_name(name), ByteVal::from_str_radix(key, 16)) {
+                        (Some(input), Ok(key)) if key < 16 => Some((input, key)),
+                        _ => {
+                            eprintln!("Ignoring gamepad binding {} = {}", name, key);
+                            None
+                        }
+                    }
+                }).collect();
+                (bindings, true)
+            },
+            _ if index == 0 => {
+                let mut bindings = direction_bindings(Some(DEFAULT_UP), Some(DEFAULT_DOWN),
+                                                      Some(DEFAULT_LEFT), Some(DEFAULT_RIGHT));
+                bindings.insert(GamepadInput::Button(Button::A), DEFAULT_FIRE);
+                (bindings, false)
+            },
+            _ => (HashMap::new(), false)
+        };
+
+        Player {
+            controller: None,
+            bindings:   bindings,
+            configured: configured,
+            deadzone:   deadzone,
+            held:       Vec::new()
+        }
+    }
+
+    fn owns(&self, instance_id: u32) -> bool {
+        self.controller.as_ref().map_or(false, |c| c.instance_id() == instance_id)
+    }
+}
+
+// Controllers are given to players in the order they are connected, and a
+// player's slot is freed again when their controller is unplugged
+pub struct Gamepads {
+    subsystem: GameControllerSubsystem,
+    players:   Vec<Player>
+}
+
+impl Gamepads {
+    pub fn new(subsystem: GameControllerSubsystem, configs: &[GamepadConfig]) -> Self {
+        Gamepads {
+            subsystem: subsystem,
+            players:   (0..MAX_PLAYERS).map(|i| Player::new(configs.get(i), i)).collect()
+        }
+    }
+
+    // Bind movement keys suggested by the ROM database, for players without
+    // configured bindings
+    pub fn set_game_keys(&mut self, keys: &GameKeys) {
+        let key = |name: &str| keys.get(name).map(|&k| k & 0xf);
+        if !self.players[0].configured && !keys.is_empty() {
+            let mut bindings = direction_bindings(key("player1Up").or(key("up")), key("player1Down").or(key("down")),
+                                                  key("left"), key("right"));
+            if let Some(a) = key("a") { bindings.insert(GamepadInput::Button(Button::A), a); }
+            if let Some(b) = key("b") { bindings.insert(GamepadInput::Button(Button::B), b); }
+            self.players[0].bindings = bindings;
+        }
+        if !self.players[1].configured {
+            self.players[1].bindings = direction_bindings(key("player2Up"), key("player2Down"), None, None);
+        }
+    }
+
+    pub fn device_added(&mut self, joystick_index: u32) {
+        if !self.subsystem.is_game_controller(joystick_index) {
+            return;
+        }
+        let controller = match self.subsystem.open(joystick_index) {
+            Ok(controller) => controller,
+            Err(e)         => {
+                eprintln!("Unable to open game controller: {}", e);
+                return;
+            }
+        };
+        if self.players.iter().any(|p| p.owns(controller.instance_id())) {
+            return;
+        }
+        match self.players.iter_mut().position(|p| p.controller.is_none()) {
+            Some(i) => {
+                eprintln!("Player {}: {}", i + 1, controller.name());
+                self.players[i].controller = Some(controller);
+            },
+            None    => eprintln!("Ignoring game controller {}", controller.name())
+        }
+    }
+
+    // Returns the keys the removed controller was holding, to be released
+    pub fn device_removed(&mut self, instance_id: u32) -> Vec<ByteVal> {
+        match self.players.iter_mut().find(|p| p.owns(instance_id)) {
+            Some(player) => {
+                player.controller = None;
+                let bindings = &player.bindings;
+                player.held.drain(..).filter_map(|input| bindings.get(&input).cloned()).collect()
+            },
+            None         => Vec::new()
+        }
+    }
+
+    // Returns the key bound to a button that was pressed or released
+    pub fn button(&mut self, instance_id: u32, button: Button, pressed: bool) -> Option<ByteVal> {
+        let player = self.players.iter_mut().find(|p| p.owns(instance_id))?;
+        let input = GamepadInput::Button(button);
+        player.held.retain(|&held| held != input);
+        if pressed {
+            player.held.push(input);
+        }
+        player.bindings.get(&input).cloned()
+    }
+
+    // Returns the keys whose state changed as the stick crossed the deadzone
+    pub fn axis_motion(&mut self, instance_id: u32, axis: Axis, value: i16) -> Vec<(ByteVal, bool)> {
+        let player = match self.players.iter_mut().find(|p| p.owns(instance_id)) {
+            Some(player) => player,
+            None         => return Vec::new()
+        };
+
+        let mut changes = Vec::new();
+        let directions = [(GamepadInput::AxisNegative(axis), value < -player.deadzone),
+                          (GamepadInput::AxisPositive(axis), value > player.deadzone)];
+        for &(input, active) in directions.iter() {
+            if player.held.contains(&input) == active {
+                continue;
+            }
+            if active {
+                player.held.push(input);
+            } else {
+                player.held.retain(|&held| held != input);
+            }
+            if let Some(&key) = player.bindings.get(&input) {
+                changes.push((key, active));
+            }
+        }
+        changes
+    }
+}
@@ -0,0 +1,70 @@
+use chip8::types::ByteVal;
+
+// CHIP-8 keys in the order they appear on the COSMAC VIP keypad, row by row
+pub const KEYPAD_LAYOUT: [ByteVal; 16] = [0x1, 0x2, 0x3, 0xc,
+                                      0x4, 0x5, 0x6, 0xd,
+                                      0x7, 0x8, 0x9, 0xe,
+                                      0xa, 0x0, 0xb, 0xf];
+
+// Host keys for each preset, as a 4x4 block matching KEYPAD_LAYOUT
+const COSMAC_KEYS: [&'static str; 16] = ["1", "2", "3", "4",
+                                         "Q", "W", "E", "R",
+                                         "A", "S", "D", "F",
+                                         "Z", "X", "C", "V"];
+const AZERTY_KEYS: [&'static str; 16] = ["1", "2", "3", "4",
+                                         "A", "Z", "E", "R",
+                                         "Q", "S", "D", "F",
+                                         "W", "X", "C", "V"];
+const QWERTZ_KEYS: [&'static str; 16] = ["1", "2", "3", "4",
+                                         "Q", "W", "E", "R",
+                                         "A", "S", "D", "F",
+                                         "Y", "X", "C", "V"];
+
+pub const DEFAULT_PRESET: &'static str = "cosmac";
+
+// Host key names bound to each CHIP-8 key. Names are those used by the
+// backend, e.g. SDL key names.
+#[derive(Clone, Debug)]
+pub struct Keymap {
+    bindings: Vec<Vec<String>>
+}
+
+impl Keymap {
+    fn from_block(keys: &[&'static str; 16]) -> Self {
+        let mut bindings = vec![Vec::new(); 16];
+        for (i, name) in keys.iter().enumerate() {
+            bindings[KEYPAD_LAYOUT[i] as usize].push(name.to_string());
+        }
+        Keymap { bindings: bindings }
+    }
+
+    // "cosmac" is the usual 1234/QWER/ASDF/ZXCV block, "azerty" and
+    // "qwertz" the same block on those layouts, and "hex" binds each key
+    // to its own digit
+    pub fn preset(name: &str) -> Option<Keymap> {
+        match name {
+            "cosmac" => Some(Keymap::from_block(&COSMAC_KEYS)),
+            "azerty" => Some(Keymap::from_block(&AZERTY_KEYS)),
+            "qwertz" => Some(Keymap::from_block(&QWERTZ_KEYS)),
+            "hex"    => Some(Keymap {
+                bindings: (0..16).map(|key| vec![format!("{:X}", key)]).collect()
+            }),
+            _        => None
+        }
+    }
+
+    // Replace the host keys bound to a CHIP-8 key
+    pub fn bind(&mut self, key: ByteVal, names: &[String]) {
+        self.bindings[(key & 0xf) as usize] = names.to_vec();
+    }
+
+    pub fn host_keys(&self, key: ByteVal) -> &[String] {
+        &self.bindings[(key & 0xf) as usize]
+    }
+
+    pub fn bindings(&self) -> Vec<(ByteVal, &str)> {
+        self.bindings.iter().enumerate()
+            .flat_map(|(key, names)| names.iter().map(move |name| (key as ByteVal, name.as_str())))
+            .collect()
+    }
+}
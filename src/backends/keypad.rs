@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Canvas;
+use sdl2::video::Window;
+
+use chip8::types::{Addr, ByteVal};
+use chip8::font::{FONT, FONT_GLYPH_SIZE};
+use backends::keymap::KEYPAD_LAYOUT;
+
+// Mouse events that SDL synthesises from touches, which are handled as
+// touches instead
+pub const TOUCH_MOUSE_ID: u32 = 0xffff_ffff;
+
+const KEY_COLOUR: Color = Color { r: 48, g: 48, b: 48, a: 255 };
+const KEY_PRESSED_COLOUR: Color = Color { r: 0, g: 160, b: 0, a: 255 };
+const KEY_POLLED_COLOUR: Color = Color { r: 120, g: 90, b: 0, a: 255 };
+const LEGEND_COLOUR: Color = Color { r: 224, g: 224, b: 224, a: 255 };
+
+// A 4x4 keypad drawn as a square on the right of the window, as tall as
+// the window. Clicks and touches on it press keys.
+pub struct OnScreenKeypad {
+    mouse_key:   Option<ByteVal>,
+    finger_keys: HashMap<i64, ByteVal>,
+    // Keys shown as pressed and as polled when the keypad was last drawn
+    drawn:       [bool; 16],
+    drawn_polls: [bool; 16]
+}
+
+impl OnScreenKeypad {
+    pub fn new() -> Self {
+        OnScreenKeypad {
+            mouse_key:   None,
+            finger_keys: HashMap::new(),
+            drawn:       [false; 16],
+            drawn_polls: [false; 16]
+        }
+    }
+
+    // Area of a window of the given size that is left for the game
+    pub fn game_area(width: u32, height: u32) -> (u32, u32) {
+        (width.saturating_sub(height), height)
+    }
+
+    fn key_rect(width: u32, height: u32, index: usize) -> Rect {
+        let size = height / 4;
+        let left = width.saturating_sub(height);
+        Rect::new((left + (index as u32 % 4) * size) as i32, ((index as u32 / 4) * size) as i32, size, size)
+    }
+
+    pub fn key_at(width: u32, height: u32, x: i32, y: i32) -> Option<ByteVal> {
+        (0..16).find(|&i| OnScreenKeypad::key_rect(width, height, i).contains_point((x, y)))
+               .map(|i| KEYPAD_LAYOUT[i])
+    }
+
+    pub fn needs_redraw(&self, key_pressed: &[bool; 16], polled: &[bool; 16]) -> bool {
+        self.drawn != *key_pressed || self.drawn_polls != *polled
+    }
+
+    // Keys the program has recently tested are shown in a different colour
+    pub fn draw(&mut self, canvas: &mut Canvas<Window>, key_pressed: &[bool; 16], polled: &[bool; 16]) {
+        let (width, height) = canvas.output_size().expect("canvas.output_size failed");
+
+        for i in 0..16 {
+            let key = KEYPAD_LAYOUT[i];
+            let rect = OnScreenKeypad::key_rect(width, height, i);
+            let border = (rect.width() / 16).max(1);
+
+            canvas.set_draw_color(if key_pressed[key as usize] {
+                KEY_PRESSED_COLOUR
+            } else if polled[key as usize] {
+                KEY_POLLED_COLOUR
+            } else {
+                KEY_COLOUR
+            });
+            canvas.fill_rect(Rect::new(rect.x() + border as i32, rect.y() + border as i32,
+                                       rect.width() - border * 2, rect.height() - border * 2))
+                  .expect("canvas.fill_rect failed");
+
+            // Label each key with its glyph from the CHIP-8 font
+            let scale = (rect.width() / 10).max(1);
+            let (left, top) = (rect.x() + (rect.width() - scale * 4) as i32 / 2,
+                               rect.y() + (rect.height() - scale * 5) as i32 / 2);
+            canvas.set_draw_color(LEGEND_COLOUR);
+            for row in 0..FONT_GLYPH_SIZE {
+                let bits = FONT[key as Addr * FONT_GLYPH_SIZE + row];
+                for col in 0..4 {
+                    if bits & (0x80 >> col) != 0 {
+                        canvas.fill_rect(Rect::new(left + (col * scale) as i32, top + (row as u32 * scale) as i32,
+                                                   scale, scale))
+                              .expect("canvas.fill_rect failed");
+                    }
+                }
+            }
+        }
+
+        self.drawn = *key_pressed;
+        self.drawn_polls = *polled;
+    }
+
+    pub fn mouse_down(&mut self, key: ByteVal) {
+        self.mouse_key = Some(key);
+    }
+
+    // Returns the key the mouse was holding
+    pub fn mouse_up(&mut self) -> Option<ByteVal> {
+        self.mouse_key.take()
+    }
+
+    pub fn finger_down(&mut self, finger_id: i64, key: ByteVal) {
+        self.finger_keys.insert(finger_id, key);
+    }
+
+    // Returns the key the finger was holding
+    pub fn finger_up(&mut self, finger_id: i64) -> Option<ByteVal> {
+        self.finger_keys.remove(&finger_id)
+    }
+}
@@ -1,8 +1,22 @@
 
 pub mod sdl;
 pub mod textui;
+pub mod keymap;
+pub mod gamepad;
+pub mod keypad;
+pub mod polling;
+pub mod overlay;
+pub mod palette;
+pub mod phosphor;
+pub mod crt;
+pub mod cells;
 
-use chip8::core::{RcRefKeyboardInterface, RcRefDisplayInterface};
+use std::path::Path;
+
+use chip8::link::FrontendIO;
+use chip8::romdb::GameKeys;
+use chip8::recorder::RecordFormat;
+use config::Settings;
 
 #[derive(Clone)]
 #[derive(Copy)]
@@ -12,15 +26,31 @@ pub enum BackendType {
 }
 
 pub trait Backend {
-    fn get_keyboard_interface(&self) -> RcRefKeyboardInterface;
-    fn get_display_interface(&self) -> RcRefDisplayInterface;
+    // Per-ROM settings from the ROM database
+    fn set_palette(&mut self, palette: &[u32]);
+    fn set_game_keys(&mut self, keys: &GameKeys);
+
+    fn set_title(&mut self, title: &str);
+
+    // Record from the start, e.g. when asked on the command line
+    fn start_recording(&mut self, path: &Path, format: RecordFormat);
 
     fn run(&mut self);
 }
 
-pub fn get_backend(backend_type: BackendType) -> Box<Backend> {
+// The format for recordings started with the hotkey
+pub fn record_format(settings: &Settings) -> RecordFormat {
+    let name = settings.video.record_format.as_ref().map_or("gif", |name| name.as_str());
+    RecordFormat::from_name(name).unwrap_or_else(|| {
+        eprintln!("Unknown recording format {}", name);
+        RecordFormat::Gif
+    })
+}
+
+// The backend talks to the core through its end of the link
+pub fn get_backend(backend_type: BackendType, settings: &Settings, io: FrontendIO) -> Box<dyn Backend> {
     match backend_type {
-        BackendType::TUI => Box::new(textui::TextUI::new()),
-        BackendType::SDL => Box::new(sdl::SDL::new())
+        BackendType::TUI => Box::new(textui::TextUI::new(settings, io)),
+        BackendType::SDL => Box::new(sdl::SDL::new(settings, io))
     }
 }
@@ -0,0 +1,77 @@
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{BlendMode, Canvas};
+use sdl2::video::Window;
+
+// 3x5 glyphs for overlay text, one row per entry with the leftmost pixel in
+// bit 2. Letters are drawn in upper case.
+const GLYPH_WIDTH: u32 = 3;
+const GLYPH_HEIGHT: u32 = 5;
+
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        '0' => [7, 5, 5, 5, 7], '1' => [2, 6, 2, 2, 7], '2' => [7, 1, 7, 4, 7], '3' => [7, 1, 7, 1, 7],
+        '4' => [5, 5, 7, 1, 1], '5' => [7, 4, 7, 1, 7], '6' => [7, 4, 7, 5, 7], '7' => [7, 1, 2, 2, 2],
+        '8' => [7, 5, 7, 5, 7], '9' => [7, 5, 7, 1, 7], 'A' => [2, 5, 7, 5, 5], 'B' => [6, 5, 6, 5, 6],
+        'C' => [3, 4, 4, 4, 3], 'D' => [6, 5, 5, 5, 6], 'E' => [7, 4, 6, 4, 7], 'F' => [7, 4, 6, 4, 4],
+        'G' => [3, 4, 5, 5, 3], 'H' => [5, 5, 7, 5, 5], 'I' => [7, 2, 2, 2, 7], 'J' => [1, 1, 1, 5, 2],
+        'K' => [5, 5, 6, 5, 5], 'L' => [4, 4, 4, 4, 7], 'M' => [5, 7, 7, 5, 5], 'N' => [6, 5, 5, 5, 5],
+        'O' => [2, 5, 5, 5, 2], 'P' => [6, 5, 6, 4, 4], 'Q' => [2, 5, 5, 6, 3], 'R' => [6, 5, 6, 5, 5],
+        'S' => [3, 4, 2, 1, 6], 'T' => [7, 2, 2, 2, 2], 'U' => [5, 5, 5, 5, 7], 'V' => [5, 5, 5, 5, 2],
+        'W' => [5, 5, 7, 7, 5], 'X' => [5, 5, 2, 5, 5], 'Y' => [5, 5, 2, 2, 2], 'Z' => [7, 1, 2, 4, 7],
+        '=' => [0, 7, 0, 7, 0], '-' => [0, 0, 7, 0, 0], '.' => [0, 0, 0, 0, 2], ':' => [0, 2, 0, 2, 0],
+        _   => [0, 0, 0, 0, 0]
+    }
+}
+
+const TEXT_COLOUR: Color = Color { r: 255, g: 255, b: 255, a: 255 };
+const STRIP_COLOUR: Color = Color { r: 0, g: 0, b: 0, a: 160 };
+
+// Text drawn in a translucent strip along the bottom of an area
+pub struct TextOverlay {
+    drawn: String
+}
+
+impl TextOverlay {
+    pub fn new() -> Self {
+        TextOverlay { drawn: String::new() }
+    }
+
+    pub fn needs_redraw(&self, text: &str) -> bool {
+        self.drawn != text
+    }
+
+    pub fn draw(&mut self, canvas: &mut Canvas<Window>, width: u32, height: u32, text: &str) {
+        self.drawn = text.into();
+        let scale = (height / 80).max(1);
+        let strip_height = (GLYPH_HEIGHT + 2) * scale;
+        // A window too short for the strip goes without
+        if text.is_empty() || height < strip_height {
+            return;
+        }
+
+        canvas.set_blend_mode(BlendMode::Blend);
+        canvas.set_draw_color(STRIP_COLOUR);
+        canvas.fill_rect(Rect::new(0, (height - strip_height) as i32, width, strip_height))
+              .expect("canvas.fill_rect failed");
+        canvas.set_blend_mode(BlendMode::None);
+
+        canvas.set_draw_color(TEXT_COLOUR);
+        let top = height - strip_height + scale;
+        for (i, c) in text.chars().enumerate() {
+            let left = scale + i as u32 * (GLYPH_WIDTH + 1) * scale;
+            if left + GLYPH_WIDTH * scale > width {
+                break;
+            }
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for col in 0..GLYPH_WIDTH {
+                    if bits & (4 >> col) != 0 {
+                        canvas.fill_rect(Rect::new((left + col * scale) as i32, (top + row as u32 * scale) as i32,
+                                                   scale, scale))
+                              .expect("canvas.fill_rect failed");
+                    }
+                }
+            }
+        }
+    }
+}
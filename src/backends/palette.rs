@@ -0,0 +1,121 @@
+use chip8::types::ByteVal;
+use chip8::romdb::parse_colour;
+use config::VideoConfig;
+
+// Colours as 0xrrggbb, indexed by a pixel's bit planes: background, plane 1,
+// plane 2, then both planes
+pub type Colours = [u32; 4];
+
+pub const DEFAULT_PALETTE: &'static str = "green";
+
+const PRESETS: [(&'static str, Colours); 5] = [
+    ("green",         [0x000000, 0x33ff33, 0x117711, 0xaaffaa]),
+    ("amber",         [0x000000, 0xffb000, 0x805800, 0xffe0a0]),
+    ("lcd",           [0x9bbc0f, 0x0f380f, 0x8bac0f, 0x306230]),
+    ("high-contrast", [0x000000, 0xffffff, 0xffff00, 0x00ffff]),
+    ("octo",          [0x996600, 0xffcc00, 0xff6600, 0x662200])
+];
+
+#[derive(Clone, Debug)]
+pub struct Palette {
+    pub name:    String,
+    pub colours: Colours
+}
+
+impl Palette {
+    // Colours that are not given are taken from the base palette, so a
+    // palette of two colours only changes the background and plane 1
+    fn from_list(name: &str, colours: &[u32], base: &Colours) -> Palette {
+        let mut palette = Palette { name: name.into(), colours: *base };
+        for (i, &colour) in colours.iter().take(4).enumerate() {
+            palette.colours[i] = colour;
+        }
+        palette
+    }
+
+    pub fn colour(&self, pixel: ByteVal) -> u32 {
+        self.colours[(pixel & 0x3) as usize]
+    }
+}
+
+// The built-in palettes, followed by those from the config and the ROM
+// database. The hotkey steps through them in order.
+pub struct Palettes {
+    palettes: Vec<Palette>,
+    current:  usize
+}
+
+impl Palettes {
+    pub fn new(video: &VideoConfig) -> Self {
+        let mut palettes: Vec<Palette> = PRESETS.iter()
+            .map(|&(name, colours)| Palette { name: name.into(), colours: colours })
+            .collect();
+
+        let mut names: Vec<&String> = video.palettes.keys().collect();
+        names.sort();
+        for name in names {
+            let colours: Vec<u32> = video.palettes[name].iter().filter_map(|c| {
+                parse_colour(c).or_else(|| {
+                    eprintln!("Ignoring colour {} in palette {}", c, name);
+                    None
+                })
+            }).collect();
+            palettes.retain(|p| p.name != *name);
+            palettes.push(Palette::from_list(name, &colours, &PRESETS[0].1));
+        }
+
+        let mut palettes = Palettes { palettes: palettes, current: 0 };
+        let selected = video.palette.clone().unwrap_or(DEFAULT_PALETTE.into());
+        if !palettes.select(&selected) {
+            eprintln!("Unknown palette {}", selected);
+        }
+
+        // Foreground and background override the selected palette
+        if video.foreground.is_some() || video.background.is_some() {
+            let mut custom = palettes.current().clone();
+            custom.name = "custom".into();
+            if let Some(colour) = video.background.as_ref().and_then(|c| parse_colour(c)) {
+                custom.colours[0] = colour;
+            }
+            if let Some(colour) = video.foreground.as_ref().and_then(|c| parse_colour(c)) {
+                custom.colours[1] = colour;
+            }
+            palettes.add(custom);
+        }
+        palettes
+    }
+
+    // Add the colours suggested by the ROM database and switch to them
+    pub fn add_rom_palette(&mut self, colours: &[u32]) {
+        if colours.is_empty() {
+            return;
+        }
+        let palette = Palette::from_list("rom", colours, &self.current().colours);
+        self.add(palette);
+    }
+
+    fn add(&mut self, palette: Palette) {
+        self.palettes.retain(|p| p.name != palette.name);
+        self.palettes.push(palette);
+        self.current = self.palettes.len() - 1;
+    }
+
+    pub fn select(&mut self, name: &str) -> bool {
+        match self.palettes.iter().position(|p| p.name == name) {
+            Some(i) => {
+                self.current = i;
+                true
+            },
+            None    => false
+        }
+    }
+
+    pub fn next(&mut self) -> &Palette {
+        self.current = (self.current + 1) % self.palettes.len();
+        self.current()
+    }
+
+    pub fn current(&self) -> &Palette {
+        &self.palettes[self.current]
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::VecDeque;
+
+use config::VideoConfig;
+
+pub const DEFAULT_DECAY: f32 = 0.6;
+pub const DEFAULT_BLEND_FRAMES: usize = 3;
+
+// Residual brightness below this is treated as fully faded
+const FADED: u32 = 4;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Persistence {
+    Off,
+    // Unlit pixels fade back to the background, keeping this fraction of
+    // their colour each frame
+    Decay(f32),
+    // Each frame is the average of the last N
+    Blend(usize)
+}
+
+impl Persistence {
+    pub fn from_config(video: &VideoConfig) -> Persistence {
+        let decay = video.decay.unwrap_or(DEFAULT_DECAY).max(0.0).min(0.95);
+        let frames = video.blend_frames.unwrap_or(DEFAULT_BLEND_FRAMES).max(1);
+        match video.persistence.as_ref().map(|p| p.as_str()) {
+            Some("decay") => Persistence::Decay(decay),
+            Some("blend") => Persistence::Blend(frames),
+            Some("off")   => Persistence::Off,
+            Some(other)   => {
+                eprintln!("Unknown persistence mode {}", other);
+                Persistence::Off
+            },
+            None          => Persistence::Off
+        }
+    }
+}
+
+fn channels(colour: u32) -> [f32; 3] {
+    [((colour >> 16) & 0xff) as f32, ((colour >> 8) & 0xff) as f32, (colour & 0xff) as f32]
+}
+
+fn colour(channels: [f32; 3]) -> u32 {
+    0xff00_0000 | ((channels[0] as u32) << 16) | ((channels[1] as u32) << 8) | channels[2] as u32
+}
+
+fn difference(a: u32, b: u32) -> u32 {
+    let (a, b) = (channels(a), channels(b));
+    (0..3).map(|i| (a[i] - b[i]).abs() as u32).max().unwrap_or(0)
+}
+
+// Post-processing of ARGB frames so that pixels erased and redrawn by XOR
+// don't flicker, the way they didn't on a CRT
+pub struct Phosphor {
+    mode:    Persistence,
+    // The mode restored when persistence is toggled back on
+    enabled: Persistence,
+    shown:   Vec<u32>,
+    history: VecDeque<Vec<u32>>,
+    fading:  bool
+}
+
+impl Phosphor {
+    pub fn new(mode: Persistence) -> Self {
+        Phosphor {
+            mode:    mode,
+            enabled: if mode == Persistence::Off { Persistence::Decay(DEFAULT_DECAY) } else { mode },
+            shown:   Vec::new(),
+            history: VecDeque::new(),
+            fading:  false
+        }
+    }
+
+    pub fn toggle(&mut self) -> Persistence {
+        self.mode = if self.mode == Persistence::Off { self.enabled } else { Persistence::Off };
+        self.reset();
+        self.mode
+    }
+
+    pub fn reset(&mut self) {
+        self.shown.clear();
+        self.history.clear();
+        self.fading = false;
+    }
+
+    // Persistence changes pixels outside those the program drew
+    pub fn is_enabled(&self) -> bool {
+        self.mode != Persistence::Off
+    }
+
+    // Whether the last frame shown was still fading, so the display needs
+    // drawing again even if it hasn't changed
+    pub fn is_fading(&self) -> bool {
+        self.fading
+    }
+
+    // Lit pixels are anything other than the background colour, and are
+    // shown at full brightness straight away
+    pub fn apply(&mut self, frame: &mut Vec<u32>, background: u32) {
+        self.fading = false;
+        match self.mode {
+            Persistence::Off           => {},
+            Persistence::Decay(decay)  => {
+                if self.shown.len() != frame.len() {
+                    self.shown = frame.clone();
+                    return;
+                }
+                let background = channels(background);
+                for (pixel, shown) in frame.iter_mut().zip(self.shown.iter_mut()) {
+                    if (*pixel | 0xff00_0000) == colour(background) {
+                        let previous = channels(*shown);
+                        let mut faded = [0.0; 3];
+                        for i in 0..3 {
+                            faded[i] = background[i] + (previous[i] - background[i]) * decay;
+                        }
+                        if difference(colour(faded), *pixel) > FADED {
+                            *pixel = colour(faded);
+                            self.fading = true;
+                        }
+                    }
+                    *shown = *pixel;
+                }
+            },
+            Persistence::Blend(frames) => {
+                if self.history.front().map_or(false, |old| old.len() != frame.len()) {
+                    self.history.clear();
+                }
+                self.history.push_back(frame.clone());
+                while self.history.len() > frames {
+                    self.history.pop_front();
+                }
+                self.fading = self.history.iter().any(|old| old != frame);
+                let count = self.history.len() as f32;
+                for (i, pixel) in frame.iter_mut().enumerate() {
+                    let mut sum = [0.0; 3];
+                    for old in self.history.iter() {
+                        let c = channels(old[i]);
+                        for j in 0..3 {
+                            sum[j] += c[j];
+                        }
+                    }
+                    *pixel = colour([sum[0] / count, sum[1] / count, sum[2] / count]);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::{Duration, Instant};
+
+use chip8::types::ByteVal;
+use backends::keymap::Keymap;
+
+// Keys stay in the hints for this long after the ROM last tested them
+pub const POLL_HINT_SECS: u64 = 3;
+
+// When the running program last tested each key with EX9E/EXA1, and last
+// waited for any key with FX0A
+pub struct PollHistory {
+    last_polled: [Option<Instant>; 16],
+    last_wait:   Option<Instant>
+}
+
+impl PollHistory {
+    pub fn new() -> Self {
+        PollHistory {
+            last_polled: [None; 16],
+            last_wait:   None
+        }
+    }
+
+    // None records a wait for any key
+    pub fn record(&mut self, key: Option<ByteVal>) {
+        let now = Instant::now();
+        match key {
+            Some(key) => self.last_polled[(key & 0xf) as usize] = Some(now),
+            None      => self.last_wait = Some(now)
+        }
+    }
+
+    fn is_recent(time: Option<Instant>) -> bool {
+        time.map_or(false, |time| time.elapsed() < Duration::from_secs(POLL_HINT_SECS))
+    }
+
+    pub fn recent(&self) -> [bool; 16] {
+        let mut recent = [false; 16];
+        for key in 0..16 {
+            recent[key] = PollHistory::is_recent(self.last_polled[key]);
+        }
+        recent
+    }
+
+    pub fn waiting_for_any_key(&self) -> bool {
+        PollHistory::is_recent(self.last_wait)
+    }
+
+    // e.g. "5=W 4=Q 6=E", with the first host key bound to each polled key,
+    // or just "5 4 6" without a keymap
+    pub fn hint_text(&self, keymap: Option<&Keymap>) -> String {
+        let mut hints: Vec<String> = self.recent().iter().enumerate()
+            .filter(|&(_, &polled)| polled)
+            .map(|(key, _)| match keymap.and_then(|keymap| keymap.host_keys(key as ByteVal).first()) {
+                Some(name) => format!("{:X}={}", key, name),
+                None       => format!("{:X}", key)
+            })
+            .collect();
+        if self.waiting_for_any_key() {
+            hints.push("ANY KEY".into());
+        }
+        hints.join(" ")
+    }
+}
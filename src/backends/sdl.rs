@@ -1,214 +1,655 @@
 
-use std::sync::{Arc, Mutex};
-use std::thread;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 use std::time::Duration;
 
 use sdl2;
 use sdl2::EventPump;
-use sdl2::render::Canvas;
-use sdl2::video::Window;
-use sdl2::pixels::Color;
+use sdl2::AudioSubsystem;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{FullscreenType, Window, WindowContext};
+use sdl2::pixels::{Color, PixelFormatEnum};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 use sdl2::rect::Rect;
 
-use backends::Backend;
+use backends::{Backend, record_format};
+use backends::gamepad::Gamepads;
+use backends::keypad::{OnScreenKeypad, TOUCH_MOUSE_ID};
+use backends::keymap::Keymap;
+use backends::polling::PollHistory;
+use backends::overlay::TextOverlay;
+use backends::palette::{Palette, Palettes};
+use backends::phosphor::{Persistence, Phosphor};
+use backends::crt::CrtEffects;
 use chip8::types::ByteVal;
-use chip8::core::{KeyboardInterface, DisplayInterface};
-use chip8::core::{RcRefKeyboardInterface, RcRefDisplayInterface};
-use chip8::display_buffer::DisplayBuffer;
-
-pub struct IOState {
-    key_pressed: [bool; 16],
-    display_buffer: DisplayBuffer,
-    display_changed: bool
+use chip8::display_buffer::Frame;
+use chip8::link::{FrontendIO, KeyState, AudioEvent};
+use chip8::romdb::GameKeys;
+use chip8::screenshot::save_screenshots;
+use chip8::recorder::{Recorder, RecordFormat};
+use config::Settings;
+
+pub struct SDL {
+    canvas:         Canvas<Window>,
+    event_pump:     EventPump,
+    audio:          AudioSubsystem,
+    sample_queue:   Option<AudioQueue<u8>>,
+    keymap:         HashMap<Keycode, ByteVal>,
+    game_keys:      HashMap<Keycode, ByteVal>,
+    gamepads:       Gamepads,
+    keypad:         Option<OnScreenKeypad>,
+    // Host keys shown in the key polling hints
+    hint_keymap:    Keymap,
+    hints:          Option<TextOverlay>,
+    palettes:       Palettes,
+    phosphor:       Phosphor,
+    crt:            CrtEffects,
+    screenshot_dir: PathBuf,
+    last_vblank:    u64,
+    recorder:       Option<Recorder>,
+    record_format:  RecordFormat,
+    integer_scaling: bool,
+    audio_enabled:  bool,
+    volume:         f32,
+    scaled_samples: Vec<ByteVal>,
+    sample_looped:  bool,
+    polls:          PollHistory,
+    display_changed: bool,
+    io:             FrontendIO
 }
 
-type RcRefIOState = Arc<Mutex<IOState>>;
+const PIXEL_WIDTH: usize = 10;
+const PIXEL_HEIGHT: usize = 10;
+
+// RCA VP-590 colour board palettes
+const VP590_BACKGROUND: [(u8, u8, u8); 4] = [(0, 0, 128), (0, 0, 0), (0, 128, 0), (128, 0, 0)];
+const VP590_FOREGROUND: [(u8, u8, u8); 8] = [(0, 0, 0), (255, 0, 0), (0, 0, 255), (255, 0, 255),
+                                             (0, 255, 0), (255, 255, 0), (0, 255, 255), (255, 255, 255)];
 
-impl KeyboardInterface for IOState {
-    fn key_pressed(&self, key: ByteVal) -> bool {
-        self.key_pressed[key as usize]
-    }
-    
-    fn wait_for_key(&self) -> ByteVal {
-        0
-    }
+fn argb(colour: (u8, u8, u8)) -> u32 {
+    ((colour.0 as u32) << 16) | ((colour.1 as u32) << 8) | colour.2 as u32
 }
 
-impl DisplayInterface for IOState {
-    fn dimensions(&self) -> (usize, usize) {
-        self.display_buffer.dimensions()
+// Whole multiples of the display size keep pixels square and even, while
+// fractional scaling fills as much of the window as the aspect ratio allows.
+// Either way the display is centred with black bars around it.
+fn letterbox(area: (u32, u32), size: (usize, usize), integer_scaling: bool) -> Rect {
+    let (width, height) = (size.0 as f32, size.1 as f32);
+    let mut scale = (area.0 as f32 / width).min(area.1 as f32 / height);
+    if integer_scaling && scale >= 1.0 {
+        scale = scale.floor();
     }
-    
-    fn clear(&mut self) {
-        self.display_changed = true;
-        self.display_buffer.clear();
-    }
-    
-    fn read_pixel(&self, x: ByteVal, y: ByteVal) -> ByteVal {
-        self.display_buffer.read_pixel(x, y)
+
+    let (dest_width, dest_height) = ((width * scale) as u32, (height * scale) as u32);
+    Rect::new(((area.0 - dest_width) / 2) as i32, ((area.1 - dest_height) / 2) as i32,
+              dest_width.max(1), dest_height.max(1))
+}
+
+// A streaming texture with a texel per display pixel, or several when CRT
+// effects are on, recreated when its size changes
+struct Screen<'a> {
+    creator:         &'a TextureCreator<WindowContext>,
+    texture:         Option<Texture<'a>>,
+    texture_size:    (usize, usize),
+    size:            (usize, usize),
+    // The frame in the texture, when only changed rows need uploading
+    sequence:        Option<u64>,
+    // Window pixels per display pixel when last drawn
+    scale:           usize,
+    integer_scaling: bool
+}
+
+impl<'a> Screen<'a> {
+    fn new(creator: &'a TextureCreator<WindowContext>, integer_scaling: bool) -> Self {
+        Screen {
+            creator:         creator,
+            texture:         None,
+            texture_size:    (0, 0),
+            size:            (0, 0),
+            sequence:        None,
+            scale:           1,
+            integer_scaling: integer_scaling
+        }
     }
-    
-    fn write_pixel(&mut self, x: ByteVal, y: ByteVal, val: ByteVal) {
-        self.display_changed = true;
-        self.display_buffer.write_pixel(x, y, val);
+
+    // Upload the whole frame next time, e.g. after a palette change
+    fn invalidate(&mut self) {
+        self.sequence = None;
     }
-    
-    fn write_pixel_xor(&mut self, x: ByteVal, y: ByteVal, val: ByteVal) -> bool {
-        self.display_changed = true;
-        self.display_buffer.write_pixel_xor(x, y, val)
+
+    // Post-processing changes every pixel, so `partial` is only true when
+    // the ARGB frame differs from the last one just where the program drew
+    fn update(&mut self, frame: &Frame, argb: Vec<u32>, crt: &CrtEffects, partial: bool) {
+        let size = frame.dimensions();
+        let shown = self.sequence.take();
+        self.size = size;
+        if partial && !crt.is_enabled() && self.texture.is_some() && self.texture_size == size {
+            if shown == Some(frame.sequence) {
+                self.sequence = shown;
+                return;
+            }
+            if shown.map_or(false, |sequence| frame.follows(sequence)) {
+                self.update_rects(frame, &argb);
+                self.sequence = Some(frame.sequence);
+                return;
+            }
+        }
+
+        let (argb, texture_size) = if crt.is_enabled() { crt.apply(&argb, size) } else { (argb, size) };
+
+        if self.texture.is_none() || self.texture_size != texture_size {
+            self.texture = Some(self.creator.create_texture_streaming(PixelFormatEnum::ARGB8888,
+                                                                      texture_size.0 as u32, texture_size.1 as u32)
+                                    .expect("Unable to create display texture"));
+            self.texture_size = texture_size;
+        }
+
+        let mut bytes = Vec::with_capacity(argb.len() * 4);
+        for pixel in argb {
+            bytes.extend_from_slice(&pixel.to_ne_bytes());
+        }
+        if let Some(ref mut texture) = self.texture {
+            texture.update(None, &bytes, texture_size.0 * 4).expect("Unable to update display texture");
+        }
+        self.sequence = Some(frame.sequence);
     }
 
-    fn write_pixel_row(&mut self, x: ByteVal, y : ByteVal, rowval: ByteVal) {
-        self.display_changed = true;
-        self.display_buffer.write_pixel_row(x, y, rowval);
+    fn update_rects(&mut self, frame: &Frame, argb: &[u32]) {
+        let width = frame.width;
+        if let Some(ref mut texture) = self.texture {
+            for (x, y, w, h) in frame.dirty.rects() {
+                let mut bytes = Vec::with_capacity(w * h * 4);
+                for row in y..y + h {
+                    for pixel in &argb[row * width + x..row * width + x + w] {
+                        bytes.extend_from_slice(&pixel.to_ne_bytes());
+                    }
+                }
+                texture.update(Rect::new(x as i32, y as i32, w as u32, h as u32), &bytes, w * 4)
+                       .expect("Unable to update display texture");
+            }
+        }
     }
-    
-    fn write_pixel_row_xor(&mut self, x: ByteVal, y : ByteVal, rowval: ByteVal) -> bool {
-        self.display_changed = true;
-        self.display_buffer.write_pixel_row_xor(x, y, rowval)
+
+    // Draw into the top-left area of the canvas, returning where the display
+    // ended up
+    fn draw(&mut self, canvas: &mut Canvas<Window>, width: u32, height: u32) -> Rect {
+        let dest = letterbox((width, height), self.size, self.integer_scaling);
+        self.scale = ((dest.width() as f32 / self.size.0.max(1) as f32).round() as usize).max(1);
+        if let Some(ref texture) = self.texture {
+            canvas.copy(texture, None, dest).expect("canvas.copy failed");
+        }
+        dest
     }
 }
 
-pub struct SDL {
-    canvas:      Canvas<Window>,
-    event_pump:  EventPump,
-    pub iostate: RcRefIOState
+// Host keys for the actions named in the ROM database, in addition to the
+// hex keypad
+fn game_keycode(action: &str) -> Option<Keycode> {
+    match action {
+        "up"          => Some(Keycode::Up),
+        "down"        => Some(Keycode::Down),
+        "left"        => Some(Keycode::Left),
+        "right"       => Some(Keycode::Right),
+        "a"           => Some(Keycode::Space),
+        "b"           => Some(Keycode::LShift),
+        "player1Up"   => Some(Keycode::W),
+        "player1Down" => Some(Keycode::S),
+        "player2Up"   => Some(Keycode::Up),
+        "player2Down" => Some(Keycode::Down),
+        _             => None
+    }
 }
 
-const PIXEL_WIDTH: usize = 10;
-const PIXEL_HEIGHT: usize = 10;
-
 impl SDL {
-    pub fn new() -> Self {
+    pub fn new(settings: &Settings, io: FrontendIO) -> Self {
         let sdl_context = sdl2::init().unwrap();
         let video_subsystem = sdl_context.video().unwrap();
 
-        let display_buffer = DisplayBuffer::new();
-        let sz = display_buffer.dimensions();
+        let sz = io.frames.front().dimensions();
+        let show_keypad = settings.video.keypad.unwrap_or(false);
+        let (width, height) = (sz.0 * settings.video.pixel_width.unwrap_or(PIXEL_WIDTH),
+                               sz.1 * settings.video.pixel_height.unwrap_or(PIXEL_HEIGHT));
         
-        let window = video_subsystem.window("rust-sdl2 demo: Video",
-                                            (sz.0 * PIXEL_WIDTH) as u32,
-                                            (sz.1 * PIXEL_HEIGHT) as u32)
+        let window = video_subsystem.window("rs-chip8-emu",
+                                            (if show_keypad { width + height } else { width }) as u32,
+                                            height as u32)
             .position_centered()
+            .resizable()
             .opengl()
             .build()
             .unwrap();
         
         let mut canvas = window.into_canvas().build().unwrap();
+        if settings.video.fullscreen.unwrap_or(false) {
+            canvas.window_mut().set_fullscreen(FullscreenType::Desktop).expect("Unable to enter fullscreen");
+        }
         
         canvas.set_draw_color(Color::RGB(0, 0, 0));
         canvas.clear();
         canvas.present();
         let event_pump = sdl_context.event_pump().unwrap();
+        let audio = sdl_context.audio().unwrap();
+        let game_controller = sdl_context.game_controller().unwrap();
         
         SDL {
             canvas: canvas,
             event_pump: event_pump,
-            iostate: Arc::new(Mutex::new(IOState {
-                key_pressed: [false; 16],
-                display_buffer: display_buffer,
-                display_changed: true
-            }))
+            audio: audio,
+            sample_queue: None,
+            keymap: SDL::configured_keymap(settings),
+            game_keys: HashMap::new(),
+            gamepads: Gamepads::new(game_controller, &settings.input.gamepad),
+            keypad: if show_keypad { Some(OnScreenKeypad::new()) } else { None },
+            hint_keymap: settings.input.keymap(),
+            palettes: Palettes::new(&settings.video),
+            phosphor: Phosphor::new(Persistence::from_config(&settings.video)),
+            crt: CrtEffects::from_config(&settings.video),
+            screenshot_dir: settings.video.screenshot_dir.as_ref().map_or(PathBuf::from("."), PathBuf::from),
+            last_vblank: 0,
+            recorder: None,
+            record_format: record_format(settings),
+            hints: if settings.video.key_hints.unwrap_or(true) { Some(TextOverlay::new()) } else { None },
+            integer_scaling: settings.video.scaling.as_ref().map_or(true, |scaling| scaling != "fractional"),
+            audio_enabled: settings.audio.enabled.unwrap_or(true),
+            volume: settings.audio.volume.unwrap_or(1.0).max(0.0).min(1.0),
+            scaled_samples: Vec::new(),
+            sample_looped: false,
+            polls: PollHistory::new(),
+            display_changed: true,
+            io: io
+        }
+    }
+
+    // Several host keys may be bound to the same CHIP-8 key
+    fn configured_keymap(settings: &Settings) -> HashMap<Keycode, ByteVal> {
+        let mut keymap = HashMap::new();
+        for (key, name) in settings.input.keymap().bindings() {
+            match Keycode::from_name(name) {
+                Some(keycode) => { keymap.insert(keycode, key); },
+                None          => eprintln!("Unknown key name {}", name)
+            }
         }
+        keymap
     }
 
-    fn process_keycode(key: Keycode, iostate: &RcRefIOState, key_state: bool) {
-        let mut io = iostate.lock().unwrap();
+    fn process_keycode(key: Keycode, keys: &KeyState, keymap: &HashMap<Keycode, ByteVal>,
+                       game_keys: &HashMap<Keycode, ByteVal>, key_state: bool) {
+        if let Some(&chip8_key) = keymap.get(&key) {
+            keys.set_key(chip8_key, key_state);
+        }
+
+        if let Some(&chip8_key) = game_keys.get(&key) {
+            keys.set_key(chip8_key, key_state);
+        }
         
-        match key {
-            Keycode::Num0 => io.key_pressed[0] = key_state,
-            Keycode::Num1 => io.key_pressed[1] = key_state,
-            Keycode::Num2 => io.key_pressed[2] = key_state,
-            Keycode::Num3 => io.key_pressed[3] = key_state,
-            Keycode::Num4 => io.key_pressed[4] = key_state,
-            Keycode::Num5 => io.key_pressed[5] = key_state,
-            Keycode::Num6 => io.key_pressed[6] = key_state,
-            Keycode::Num7 => io.key_pressed[7] = key_state,
-            Keycode::Num8 => io.key_pressed[8] = key_state,
-            Keycode::Num9 => io.key_pressed[9] = key_state,
-            Keycode::A => io.key_pressed[10] = key_state,
-            Keycode::B => io.key_pressed[11] = key_state,
-            Keycode::C => io.key_pressed[12] = key_state,
-            Keycode::D => io.key_pressed[13] = key_state,
-            Keycode::E => io.key_pressed[14] = key_state,
-            Keycode::F => io.key_pressed[15] = key_state,
-            _          => {}
-                
+        // CHIP-8X second keypad
+        let second_key = match key {
+            Keycode::Kp0 => Some(0),
+            Keycode::Kp1 => Some(1),
+            Keycode::Kp2 => Some(2),
+            Keycode::Kp3 => Some(3),
+            Keycode::Kp4 => Some(4),
+            Keycode::Kp5 => Some(5),
+            Keycode::Kp6 => Some(6),
+            Keycode::Kp7 => Some(7),
+            Keycode::Kp8 => Some(8),
+            Keycode::Kp9 => Some(9),
+            Keycode::KpDivide => Some(10),
+            Keycode::KpMultiply => Some(11),
+            Keycode::KpMinus => Some(12),
+            Keycode::KpPlus => Some(13),
+            Keycode::KpEnter => Some(14),
+            Keycode::KpPeriod => Some(15),
+            _          => None
         };
+        if let Some(second_key) = second_key {
+            keys.set_second_key(second_key, key_state);
+        }
     }
 
-    fn update_display(iostate: &RcRefIOState, canvas: &mut Canvas<Window>) {
-        let mut io = iostate.lock().unwrap();
-
-        if !io.display_changed {
+    fn update_display(frame: &Frame, changed: bool, key_pressed: &[bool; 16], polls: &PollHistory,
+                      canvas: &mut Canvas<Window>, screen: &mut Screen, palette: &Palette, phosphor: &mut Phosphor,
+                      crt: &CrtEffects, keypad: Option<&mut OnScreenKeypad>, hints: Option<&mut TextOverlay>, hint_keymap: &Keymap) {
+        let polled = polls.recent();
+        let hint_text = polls.hint_text(Some(hint_keymap));
+        let keypad_changed = keypad.as_ref().map_or(false, |keypad| keypad.needs_redraw(key_pressed, &polled));
+        let hints_changed = hints.as_ref().map_or(false, |hints| hints.needs_redraw(&hint_text));
+        if !changed && !keypad_changed && !hints_changed && !phosphor.is_fading() {
             return;
         }
 
-        eprintln!("Updating display");
+        // The keypad takes a square on the right of the window
+        let (output_width, output_height) = canvas.output_size().expect("canvas.output_size failed");
+        let (window_width, window_height) = match keypad {
+            Some(_) => OnScreenKeypad::game_area(output_width, output_height),
+            None    => (output_width, output_height)
+        };
 
-        let sz = io.dimensions();
+        // MegaChip programs draw to a back buffer, so don't flicker
+        let rgb = match frame.argb {
+            Some(ref argb) => {
+                phosphor.reset();
+                SDL::megachip_frame(argb)
+            },
+            None           => {
+                let mut rgb = SDL::chip8_frame(frame, palette);
+                phosphor.apply(&mut rgb, SDL::chip8_background(frame, palette));
+                rgb
+            }
+        };
+        screen.update(frame, rgb, crt, !phosphor.is_enabled());
 
         canvas.set_draw_color(Color::RGB(0, 0, 0));
         canvas.clear();
+        let dest = screen.draw(canvas, window_width, window_height);
+
+        if let Some(hints) = hints {
+            hints.draw(canvas, window_width, dest.bottom().min(window_height as i32) as u32, &hint_text);
+        }
+
+        if let Some(keypad) = keypad {
+            keypad.draw(canvas, key_pressed, &polled);
+        }
+
+        canvas.present();
+    }
 
-        canvas.set_draw_color(Color::RGB(0, 128, 0));
+    fn chip8_background(frame: &Frame, palette: &Palette) -> u32 {
+        match frame.background {
+            Some(colour) => argb(VP590_BACKGROUND[colour as usize]),
+            None         => palette.colour(0)
+        }
+    }
+
+    // The display as ARGB, in VP-590 colours when colour is enabled
+    fn chip8_frame(frame: &Frame, palette: &Palette) -> Vec<u32> {
+        let (width, height) = frame.dimensions();
+        let background = SDL::chip8_background(frame, palette);
+
+        let mut rgb = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = frame.pixel(x, y);
+                let colour = if pixel != 0 {
+                    match frame.pixel_colour(x, y) {
+                        Some(colour) => argb(VP590_FOREGROUND[colour as usize]),
+                        None         => palette.colour(pixel)
+                    }
+                } else {
+                    background
+                };
+                rgb.push(0xff00_0000 | colour);
+            }
+        }
+        rgb
+    }
+
+    // Transparent MegaChip pixels are shown as black
+    fn megachip_frame(argb: &[u32]) -> Vec<u32> {
+        argb.iter().map(|&pixel| 0xff00_0000 | pixel).collect()
+    }
+
+    // The display as the program drew it, without post-processing
+    fn current_frame(frame: &Frame, palette: &Palette) -> ((usize, usize), Vec<u32>) {
+        match frame.argb {
+            Some(ref argb) => (frame.dimensions(), SDL::megachip_frame(argb)),
+            None           => (frame.dimensions(), SDL::chip8_frame(frame, palette))
+        }
+    }
+
+    fn save_screenshot(frame: &Frame, palette: &Palette, dir: &Path, scale: usize) {
+        let (size, rgb) = SDL::current_frame(frame, palette);
+        match save_screenshots(dir, &rgb, size, scale) {
+            Ok(paths) => for path in paths {
+                eprintln!("Saved {}", path.display());
+            },
+            Err(e)    => eprintln!("Unable to save screenshot: {}", e)
+        }
+    }
 
-        for y in 0..sz.1 {
-            for x in 0..sz.0 {
-                if io.read_pixel(x as ByteVal, y as ByteVal) != 0 {
-                    //eprintln!("Setting pixel {}x{}", x, y);
-                    canvas.fill_rect(Rect::new((x * PIXEL_WIDTH) as i32,
-                                               (y * PIXEL_HEIGHT) as i32,
-                                               PIXEL_WIDTH as u32,
-                                               PIXEL_HEIGHT as u32)).expect("canvas.fill_rect failed");
+    fn toggle_recording(&mut self) {
+        match self.recorder.take() {
+            Some(recorder) => SDL::finish_recording(recorder),
+            None           => {
+                let size = self.io.frames.front().dimensions();
+                match Recorder::start_in(&self.screenshot_dir, self.record_format, size) {
+                    Ok((recorder, path)) => {
+                        eprintln!("Recording to {}", path.display());
+                        self.recorder = Some(recorder);
+                    },
+                    Err(e)               => eprintln!("Unable to start recording: {}", e)
                 }
             }
         }
-        canvas.present();
-        io.display_changed = false;
+    }
+
+    fn finish_recording(recorder: Recorder) {
+        match recorder.finish() {
+            Ok(_)  => eprintln!("Recording finished"),
+            Err(e) => eprintln!("Unable to write recording: {}", e)
+        }
+    }
+
+    // Start or stop MegaChip sample playback, and keep looped samples queued
+    fn update_audio(&mut self) {
+        // Only the latest event matters
+        if let Some(event) = self.io.audio.try_iter().last() {
+            self.sample_looped = false;
+            self.sample_queue = match event {
+                AudioEvent::Play(ref sample, looped) if self.audio_enabled => {
+                    self.sample_looped = looped;
+                    // Unsigned 8-bit samples, scaled about the midpoint
+                    let volume = self.volume;
+                    self.scaled_samples = sample.samples.iter()
+                        .map(|&s| (128.0 + (s as f32 - 128.0) * volume) as u8)
+                        .collect();
+                    let spec = AudioSpecDesired { freq: Some(sample.rate as i32), channels: Some(1), samples: None };
+                    match self.audio.open_queue::<u8, _>(None, &spec) {
+                        Ok(queue) => {
+                            queue.queue_audio(&self.scaled_samples).expect("Unable to queue sample");
+                            queue.resume();
+                            Some(queue)
+                        },
+                        Err(e) => {
+                            eprintln!("Unable to open audio device: {}", e);
+                            None
+                        }
+                    }
+                },
+                _ => None
+            };
+        }
+
+        if let (true, Some(ref queue)) = (self.sample_looped, self.sample_queue.as_ref()) {
+            if (queue.size() as usize) < self.scaled_samples.len() {
+                queue.queue_audio(&self.scaled_samples).expect("Unable to queue sample");
+            }
+        }
     }
 }
 
 
 impl Backend for SDL {
-    fn get_keyboard_interface(&self) -> RcRefKeyboardInterface {
-        self.iostate.clone()
+    fn set_palette(&mut self, palette: &[u32]) {
+        self.palettes.add_rom_palette(palette);
+        self.display_changed = true;
+    }
+
+    fn set_game_keys(&mut self, keys: &GameKeys) {
+        self.gamepads.set_game_keys(keys);
+        self.game_keys = keys.iter()
+            .filter_map(|(action, &key)| game_keycode(action).map(|keycode| (keycode, key & 0xf)))
+            .collect();
+    }
+
+    fn set_title(&mut self, title: &str) {
+        self.canvas.window_mut().set_title(title).expect("Unable to set window title");
     }
-    
-    fn get_display_interface(&self) -> RcRefDisplayInterface {
-        self.iostate.clone()
+
+    fn start_recording(&mut self, path: &Path, format: RecordFormat) {
+        let size = self.io.frames.front().dimensions();
+        match Recorder::start(path, format, size) {
+            Ok(recorder) => self.recorder = Some(recorder),
+            Err(e)       => eprintln!("Unable to start recording: {}", e)
+        }
     }
 
     fn run(&mut self) {
+        let texture_creator = self.canvas.texture_creator();
+        let mut screen = Screen::new(&texture_creator, self.integer_scaling);
+
         'running: loop {
-            let mut iostate = &self.iostate;
+            let keys = &self.io.keys;
             let mut canvas = &mut self.canvas;
+            let mut recording_toggled = false;
             
             for event in self.event_pump.poll_iter() {
                 match event {
                     Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                         break 'running;
                     },
+                    Event::KeyDown { keycode: Some(Keycode::F1), repeat: false, .. } => {
+                        self.hints = match self.hints {
+                            Some(_) => None,
+                            None    => Some(TextOverlay::new())
+                        };
+                        self.display_changed = true;
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::F2), repeat: false, .. } => {
+                        eprintln!("Palette: {}", self.palettes.next().name);
+                        screen.invalidate();
+                        self.display_changed = true;
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::F3), repeat: false, .. } => {
+                        eprintln!("Persistence: {:?}", self.phosphor.toggle());
+                        screen.invalidate();
+                        self.display_changed = true;
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::F4), repeat: false, .. } => {
+                        eprintln!("CRT effects: {}", if self.crt.toggle() { "on" } else { "off" });
+                        screen.invalidate();
+                        self.display_changed = true;
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::F12), repeat: false, .. } => {
+                        SDL::save_screenshot(self.io.frames.front(), self.palettes.current(), &self.screenshot_dir, screen.scale);
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::F9), repeat: false, .. } => {
+                        recording_toggled = true;
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::F10), repeat: false, .. } => {
+                        screen.integer_scaling = !screen.integer_scaling;
+                        self.display_changed = true;
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::F11), repeat: false, .. } => {
+                        let fullscreen = match canvas.window().fullscreen_state() {
+                            FullscreenType::Off => FullscreenType::Desktop,
+                            _                   => FullscreenType::Off
+                        };
+                        if let Err(e) = canvas.window_mut().set_fullscreen(fullscreen) {
+                            eprintln!("Unable to toggle fullscreen: {}", e);
+                        }
+                        self.display_changed = true;
+                    },
                     Event::KeyDown { keycode: Some(keycode), .. } => {
-                        SDL::process_keycode(keycode, iostate, true);
+                        SDL::process_keycode(keycode, keys, &self.keymap, &self.game_keys, true);
                     },
                     Event::KeyUp { keycode: Some(keycode), .. } => {
-                        SDL::process_keycode(keycode, iostate, false);
+                        SDL::process_keycode(keycode, keys, &self.keymap, &self.game_keys, false);
+                    },
+                    Event::ControllerDeviceAdded { which, .. } => {
+                        self.gamepads.device_added(which);
+                    },
+                    Event::ControllerDeviceRemoved { which, .. } => {
+                        for key in self.gamepads.device_removed(which) {
+                            keys.set_key(key, false);
+                        }
+                    },
+                    Event::ControllerButtonDown { which, button, .. } => {
+                        if let Some(key) = self.gamepads.button(which, button, true) {
+                            keys.set_key(key, true);
+                        }
+                    },
+                    Event::ControllerButtonUp { which, button, .. } => {
+                        if let Some(key) = self.gamepads.button(which, button, false) {
+                            keys.set_key(key, false);
+                        }
+                    },
+                    Event::ControllerAxisMotion { which, axis, value, .. } => {
+                        for (key, pressed) in self.gamepads.axis_motion(which, axis, value) {
+                            keys.set_key(key, pressed);
+                        }
+                    },
+                    Event::MouseButtonDown { which, x, y, .. } if which != TOUCH_MOUSE_ID => {
+                        // The keypad is laid out in output pixels, which
+                        // outnumber window points on HiDPI displays
+                        let (width, height) = canvas.output_size().expect("canvas.output_size failed");
+                        let (window_width, window_height) = canvas.window().size();
+                        let (x, y) = ((x as i64 * width as i64 / window_width.max(1) as i64) as i32,
+                                      (y as i64 * height as i64 / window_height.max(1) as i64) as i32);
+                        if let (Some(keypad), Some(key)) = (self.keypad.as_mut(), OnScreenKeypad::key_at(width, height, x, y)) {
+                            keypad.mouse_down(key);
+                            keys.set_key(key, true);
+                        }
+                    },
+                    Event::MouseButtonUp { which, .. } if which != TOUCH_MOUSE_ID => {
+                        if let Some(key) = self.keypad.as_mut().and_then(|keypad| keypad.mouse_up()) {
+                            keys.set_key(key, false);
+                        }
+                    },
+                    Event::FingerDown { finger_id, x, y, .. } => {
+                        let (width, height) = canvas.output_size().expect("canvas.output_size failed");
+                        let (x, y) = ((x * width as f32) as i32, (y * height as f32) as i32);
+                        if let (Some(keypad), Some(key)) = (self.keypad.as_mut(), OnScreenKeypad::key_at(width, height, x, y)) {
+                            keypad.finger_down(finger_id, key);
+                            keys.set_key(key, true);
+                        }
+                    },
+                    Event::FingerUp { finger_id, .. } => {
+                        if let Some(key) = self.keypad.as_mut().and_then(|keypad| keypad.finger_up(finger_id)) {
+                            keys.set_key(key, false);
+                        }
                     },
                     Event::Window {..} => {
-                        let mut io = iostate.lock().unwrap();
-                        io.display_changed = true;
+                        self.display_changed = true;
                     },
                     _ => {}
                 }
             }
 
-            SDL::update_display(iostate, &mut canvas);
+            for key in self.io.keys.take_polls() {
+                self.polls.record(key);
+            }
+            SDL::update_display(self.io.frames.front(), self.display_changed, &self.io.keys.pressed_keys(), &self.polls,
+                                &mut canvas, &mut screen, self.palettes.current(), &mut self.phosphor,
+                                &self.crt, self.keypad.as_mut(), self.hints.as_mut(), &self.hint_keymap);
+            self.display_changed = false;
+            self.update_audio();
+
+            if recording_toggled {
+                self.toggle_recording();
+            }
+
+            // Wake at each vblank, and at least 60 times a second
+            let vblank = self.io.vblank.wait(self.last_vblank, Duration::new(0, 1_000_000_000u32 / 60));
+            if self.io.frames.update() {
+                self.display_changed = true;
+            }
+
+            // One recorded frame per vblank
+            if let Some(ref mut recorder) = self.recorder {
+                if vblank > self.last_vblank {
+                    let (size, frame) = SDL::current_frame(self.io.frames.front(), self.palettes.current());
+                    recorder.add_frame(&frame, size, vblank - self.last_vblank);
+                }
+            }
+            self.last_vblank = vblank;
+        }
 
-            thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
+        if let Some(recorder) = self.recorder.take() {
+            SDL::finish_recording(recorder);
         }
     }
 }
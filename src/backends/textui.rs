@@ -1,108 +1,343 @@
 
 use std::io;
-use std::sync::{Arc, Mutex};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
 use std::thread;
 
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use termion::{color, cursor};
+use termion::event::Key;
+use termion::input::TermRead;
 use tui::Terminal;
 use tui::backend::RawBackend;
-use tui::widgets::{Widget, Block, Borders};
+use tui::widgets::{Widget, Block, Borders, Paragraph};
 use tui::layout::{Group, Size, Direction, Rect};
 
-use backends::Backend;
-use chip8::types::ByteVal;
-use chip8::core::{KeyboardInterface, DisplayInterface};
-use chip8::core::{RcRefKeyboardInterface, RcRefDisplayInterface};
-use chip8::display_buffer::{WIDTH, HEIGHT};
+use backends::{Backend, record_format};
+use chip8::link::FrontendIO;
+use chip8::romdb::GameKeys;
+use chip8::screenshot::save_screenshots;
+use chip8::recorder::{Recorder, RecordFormat};
+use backends::polling::{PollHistory, POLL_HINT_SECS};
+use backends::palette::Palettes;
+use backends::cells::CellMode;
+use config::Settings;
 
-pub struct IOState {
-    key_pressed: [bool; 16]
+fn rgb(colour: u32) -> color::Rgb {
+    color::Rgb((colour >> 16) as u8, (colour >> 8) as u8, colour as u8)
 }
 
-type RcRefIOState = Arc<Mutex<IOState>>;
+// Screenshots are saved at this scale as well as the native resolution.
+// The pixel size setting is for windows, so doesn't apply.
+const SCREENSHOT_SCALE: usize = 10;
 
-impl KeyboardInterface for IOState {
-    fn key_pressed(&self, key: ByteVal) -> bool {
-        self.key_pressed[key as usize]
-    }
-    
-    fn wait_for_key(&self) -> ByteVal {
-        0
-    }
+// Terminal size needed for a display of the given size, with its border
+fn display_area(mode: CellMode, size: (usize, usize)) -> Rect {
+    let (columns, rows) = mode.cells(size);
+    Rect::new(0, 0, columns as u16 + 2, rows as u16 + 2)
 }
 
-impl DisplayInterface for IOState {
-    fn dimensions(&self) -> (usize, usize) {
-        (0, 0)
-    }
-    
-    fn clear(&mut self) {
-    }
-    
-    fn read_pixel(&self, x: ByteVal, y: ByteVal) -> ByteVal {
-        0
-    }
-    
-    fn write_pixel(&mut self, x: ByteVal, y: ByteVal, val: ByteVal) {
-    }
-    
-    fn write_pixel_xor(&mut self, x: ByteVal, y: ByteVal, val: ByteVal) -> bool {
-        false
-    }
-
-    fn write_pixel_row(&mut self, x: ByteVal, y : ByteVal, rowval: ByteVal) {
-    }
-    
-    fn write_pixel_row_xor(&mut self, x: ByteVal, y : ByteVal, rowval: ByteVal) -> bool {
-        false
-    }
+// The text mode to use, or None to fit the display to the terminal
+fn text_mode(settings: &Settings) -> Option<CellMode> {
+    let name = settings.video.text_mode.as_ref().map_or("auto", |name| name.as_str());
+    CellMode::from_name(name).unwrap_or_else(|| {
+        eprintln!("Unknown text mode {}", name);
+        None
+    })
 }
 
 pub struct TextUI {
-    pub iostate: RcRefIOState
+    terminal:        Terminal<RawBackend>,
+    status:          String,
+    palettes:        Palettes,
+    // The mode asked for, and the one the display is drawn in
+    text_mode:       Option<CellMode>,
+    cell_mode:       CellMode,
+    area:            Rect,
+    terminal_size:   Rect,
+    // A message shown in the status line for a few seconds
+    notice:          Option<(String, Instant)>,
+    screenshot_dir:  PathBuf,
+    recorder:        Option<Recorder>,
+    record_format:   RecordFormat,
+    input:           Receiver<Key>,
+    last_vblank:     u64,
+    polls:           PollHistory,
+    display_changed: bool,
+    // Glyph and colours last written to each character cell, and the frame
+    // they show, so only cells that changed are written again
+    cells:           Vec<Option<(char, u32, u32)>>,
+    shown:           Option<u64>,
+    io:              FrontendIO
 }
 
 impl TextUI {
-    pub fn new() -> Self {
+    pub fn new(settings: &Settings, io: FrontendIO) -> Self {
         let backend = RawBackend::new().unwrap();
-        let mut terminal = Terminal::new(backend).unwrap();
+        let terminal = Terminal::new(backend).unwrap();
+
+        // Terminal input arrives on a thread of its own, so that the run
+        // loop can poll it
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            for key in io::stdin().keys() {
+                match key {
+                    Ok(key) => if tx.send(key).is_err() { break; },
+                    Err(_)  => break
+                }
+            }
+        });
 
-        TextUI::init(&mut terminal).expect("Initialization failed");
-        
         TextUI {
-            iostate: Arc::new(Mutex::new(IOState {
-                key_pressed: [false; 16]
-            }))
+            terminal:        terminal,
+            status:          String::new(),
+            palettes:        Palettes::new(&settings.video),
+            text_mode:       text_mode(settings),
+            cell_mode:       CellMode::HalfBlock,
+            area:            Rect::default(),
+            terminal_size:   Rect::default(),
+            notice:          None,
+            screenshot_dir:  settings.video.screenshot_dir.as_ref().map_or(PathBuf::from("."), PathBuf::from),
+            recorder:        None,
+            record_format:   record_format(settings),
+            input:           rx,
+            last_vblank:     0,
+            polls:           PollHistory::new(),
+            display_changed: true,
+            cells:           Vec::new(),
+            shown:           None,
+            io:              io
+        }
+    }
+
+    fn update_display(&mut self) -> Result<(), io::Error> {
+        // Redraw everything when the resolution or the terminal size
+        // changes, choosing the mode again. The status line goes below the
+        // border.
+        let size = self.terminal.size()?;
+        let dimensions = self.io.frames.front().dimensions();
+        let mode = self.text_mode.unwrap_or_else(|| {
+            CellMode::fitting(dimensions, ((size.width as usize).saturating_sub(2), (size.height as usize).saturating_sub(3)))
+        });
+        let area = display_area(mode, dimensions);
+        if area != self.area || mode != self.cell_mode || size != self.terminal_size {
+            self.terminal.resize(size)?;
+            self.cell_mode = mode;
+            self.area = area;
+            self.terminal_size = size;
+            self.cells.clear();
+            self.shown = None;
+            self.display_changed = true;
+            self.draw_frame()?;
+        }
+
+        if !self.display_changed {
+            return Ok(());
         }
+        self.display_changed = false;
+        self.paint_cells()
     }
 
-    pub fn init(terminal: &mut Terminal<RawBackend>) -> Result<(), io::Error> {
-        let size = Rect::new(0, 0, WIDTH as u16 + 2, HEIGHT as u16 + 2);
-        
+    // Write the cells whose pixels changed straight to the terminal. tui
+    // never draws inside the border, so leaves them alone.
+    fn paint_cells(&mut self) -> Result<(), io::Error> {
+        let frame = self.io.frames.front();
+        let dirty = match self.shown {
+            Some(sequence) if sequence == frame.sequence => return Ok(()),
+            Some(sequence) if frame.follows(sequence)    => Some(&frame.dirty),
+            _                                            => None
+        };
+        self.shown = Some(frame.sequence);
+
+        let mode = self.cell_mode;
+        let (columns, rows) = mode.cells(frame.dimensions());
+        let (cell_width, cell_height) = mode.cell_size();
+        if self.cells.len() != columns * rows {
+            self.cells = vec![None; columns * rows];
+        }
+
+        // Cells beyond the edge of the terminal are left out
+        let size = self.terminal_size;
+        let visible_columns = columns.min((size.width as usize).saturating_sub(self.area.x as usize + 2));
+        let visible_rows = rows.min((size.height as usize).saturating_sub(self.area.y as usize + 2));
+
+        let palette = self.palettes.current();
+        let mut output = String::new();
+        for row in 0..visible_rows {
+            // Dirty pixel columns, widened to whole cells
+            let span = match dirty {
+                Some(dirty) => dirty.span(row * cell_height, cell_height)
+                                    .map(|(left, right)| (left / cell_width, (right + cell_width - 1) / cell_width)),
+                None        => Some((0, columns))
+            };
+            let (left, right) = match span {
+                Some(span) => span,
+                None       => continue
+            };
+            for column in left..right.min(visible_columns) {
+                let (glyph, foreground, background) = mode.cell(frame, palette, column, row);
+                let cell = &mut self.cells[row * columns + column];
+                if *cell == Some((glyph, foreground, background)) {
+                    continue;
+                }
+                *cell = Some((glyph, foreground, background));
+                output.push_str(&format!("{}{}{}{}",
+                                         cursor::Goto(self.area.x + column as u16 + 2, self.area.y + row as u16 + 2),
+                                         color::Fg(rgb(foreground)), color::Bg(rgb(background)), glyph));
+            }
+        }
+        if output.is_empty() {
+            return Ok(());
+        }
+
+        let backend = self.terminal.backend_mut();
+        write!(backend, "{}{}{}", output, color::Fg(color::Reset), color::Bg(color::Reset))?;
+        backend.flush()
+    }
+
+    // The border, titled with the palette name, and the status line below it
+    fn draw_frame(&mut self) -> Result<(), io::Error> {
+        let area = self.area;
         Block::default()
-            .title("BLK")
+            .title(&self.palettes.current().name)
             .borders(Borders::ALL)
-            .render(terminal, &size);
-        
-        terminal.draw()
+            .render(&mut self.terminal, &area);
+
+        let status_area = Rect::new(0, area.bottom(), area.width, 1);
+        Paragraph::default()
+            .raw(true)
+            .text(&format!("{:width$}", self.status, width = status_area.width as usize))
+            .render(&mut self.terminal, &status_area);
+        self.terminal.draw()
+    }
+
+    fn save_screenshot(&mut self) {
+        let frame = self.io.frames.front();
+        let rgb = frame.to_argb(&self.palettes.current().colours);
+        let notice = match save_screenshots(&self.screenshot_dir, &rgb, frame.dimensions(), SCREENSHOT_SCALE) {
+            Ok(paths) => format!("Saved {}", paths[0].display()),
+            Err(e)    => format!("Unable to save screenshot: {}", e)
+        };
+        self.notice = Some((notice, Instant::now()));
+    }
+
+    fn toggle_recording(&mut self) {
+        let notice = match self.recorder.take() {
+            Some(recorder) => match recorder.finish() {
+                Ok(_)  => "Recording finished".into(),
+                Err(e) => format!("Unable to write recording: {}", e)
+            },
+            None           => {
+                let size = self.io.frames.front().dimensions();
+                match Recorder::start_in(&self.screenshot_dir, self.record_format, size) {
+                    Ok((recorder, path)) => {
+                        self.recorder = Some(recorder);
+                        format!("Recording to {}", path.display())
+                    },
+                    Err(e)               => format!("Unable to start recording: {}", e)
+                }
+            }
+        };
+        self.notice = Some((notice, Instant::now()));
     }
 
-    pub fn run(&mut self) {
+    // Status line below the display, listing the keys the program has
+    // recently tested, or showing a notice. Terminal keys aren't passed to
+    // the program, so no host keys are named.
+    fn update_status(&mut self) -> Result<(), io::Error> {
+        if self.notice.as_ref().map_or(false, |&(_, time)| time.elapsed() >= Duration::from_secs(POLL_HINT_SECS)) {
+            self.notice = None;
+        }
+        let hints = self.polls.hint_text(None);
+        let status = match self.notice {
+            Some((ref notice, _)) => notice.clone(),
+            None if hints.is_empty() => String::new(),
+            None                  => format!("Keys: {}", hints)
+        };
+        if status == self.status {
+            return Ok(());
+        }
+        self.status = status;
+        self.draw_frame()
     }
 }
 
 
 impl Backend for TextUI {
-    fn get_keyboard_interface(&self) -> RcRefKeyboardInterface {
-        self.iostate.clone()
+    fn set_palette(&mut self, palette: &[u32]) {
+        self.palettes.add_rom_palette(palette);
+        self.shown = None;
+        self.display_changed = true;
+    }
+
+    fn set_game_keys(&mut self, _keys: &GameKeys) {
     }
-    
-    fn get_display_interface(&self) -> RcRefDisplayInterface {
-        self.iostate.clone()
+
+    fn set_title(&mut self, _title: &str) {
+    }
+
+    fn start_recording(&mut self, path: &Path, format: RecordFormat) {
+        let size = self.io.frames.front().dimensions();
+        match Recorder::start(path, format, size) {
+            Ok(recorder) => self.recorder = Some(recorder),
+            Err(e)       => self.notice = Some((format!("Unable to start recording: {}", e), Instant::now()))
+        }
     }
 
     fn run(&mut self) {
-        loop{}
+        self.terminal.hide_cursor().expect("Unable to hide cursor");
+
+        'running: loop {
+            while let Ok(key) = self.input.try_recv() {
+                match key {
+                    Key::Esc    => break 'running,
+                    Key::F(2)   => {
+                        self.palettes.next();
+                        self.shown = None;
+                        self.display_changed = true;
+                        self.draw_frame().expect("Unable to draw display");
+                    },
+                    Key::F(9)   => self.toggle_recording(),
+                    Key::F(12)  => self.save_screenshot(),
+                    _           => {}
+                }
+            }
+
+            for key in self.io.keys.take_polls() {
+                self.polls.record(key);
+            }
+            // There's no audio in the terminal
+            while let Ok(_) = self.io.audio.try_recv() {}
+
+            self.update_display().expect("Unable to draw display");
+            self.update_status().expect("Unable to draw status line");
+
+            // Wake at each vblank, and at least 60 times a second
+            let vblank = self.io.vblank.wait(self.last_vblank, Duration::new(0, 1_000_000_000u32 / 60));
+            if self.io.frames.update() {
+                self.display_changed = true;
+            }
+
+            // One recorded frame per vblank
+            if let Some(ref mut recorder) = self.recorder {
+                if vblank > self.last_vblank {
+                    let frame = self.io.frames.front();
+                    let rgb = frame.to_argb(&self.palettes.current().colours);
+                    recorder.add_frame(&rgb, frame.dimensions(), vblank - self.last_vblank);
+                }
+            }
+            self.last_vblank = vblank;
+        }
+
+        if let Some(recorder) = self.recorder.take() {
+            if let Err(e) = recorder.finish() {
+                eprintln!("Unable to write recording: {}", e);
+            }
+        }
+
+        self.terminal.clear().expect("Unable to clear terminal");
+        self.terminal.show_cursor().expect("Unable to show cursor");
     }
 }
@@ -0,0 +1,484 @@
+use chip8::core::MemoryInterface;
+use chip8::types::{Addr, ByteVal};
+
+// External lines of the 1802: the N0-N2 I/O port selects, the data bus
+// during INP/OUT and the EF1-EF4 flag inputs
+pub trait Cdp1802Bus {
+    fn output(&mut self, port: ByteVal, val: ByteVal);
+    fn input(&mut self, port: ByteVal) -> ByteVal;
+    fn ef(&self, line: ByteVal) -> bool;
+}
+
+pub struct Cdp1802 {
+    pub r:  [u16; 16],
+    pub d:  ByteVal,
+    pub df: bool,
+    pub p:  usize,
+    pub x:  usize,
+    pub t:  ByteVal,
+    pub ie: bool,
+    pub q:  bool,
+    idle:   bool
+}
+
+impl Cdp1802 {
+    pub fn new() -> Self {
+        let mut cpu = Cdp1802 {
+            r:    [0; 16],
+            d:    0,
+            df:   false,
+            p:    0,
+            x:    0,
+            t:    0,
+            ie:   true,
+            q:    false,
+            idle: false
+        };
+        cpu.reset();
+        cpu
+    }
+
+    // Reset clears I, N, X, P and Q, sets IE and zeroes R0
+    pub fn reset(&mut self) {
+        self.r[0] = 0;
+        self.p = 0;
+        self.x = 0;
+        self.ie = true;
+        self.q = false;
+        self.idle = false;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle
+    }
+
+    // Respond to an interrupt request. Returns the machine cycles taken.
+    pub fn interrupt(&mut self) -> u32 {
+        if !self.ie {
+            return 0;
+        }
+
+        self.t = ((self.x as ByteVal) << 4) | self.p as ByteVal;
+        self.p = 1;
+        self.x = 2;
+        self.ie = false;
+        self.idle = false;
+        1
+    }
+
+    // DMA output cycle, as used by the CDP1861 to fetch display data
+    pub fn dma_out(&mut self, mem: &dyn MemoryInterface) -> ByteVal {
+        let val = mem.read_byte(self.r[0] as Addr);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        val
+    }
+
+    fn read(&self, mem: &dyn MemoryInterface, reg: usize) -> ByteVal {
+        mem.read_byte(self.r[reg] as Addr)
+    }
+
+    fn fetch_imm(&mut self, mem: &dyn MemoryInterface) -> ByteVal {
+        let p = self.p;
+        let val = self.read(mem, p);
+        self.r[p] = self.r[p].wrapping_add(1);
+        val
+    }
+
+    fn add(&mut self, a: ByteVal, b: ByteVal, carry: bool) {
+        let sum = a as u16 + b as u16 + carry as u16;
+        self.d = sum as ByteVal;
+        self.df = sum > 0xff;
+    }
+
+    // a - b, with DF set when there is no borrow
+    fn sub(&mut self, a: ByteVal, b: ByteVal, borrow: bool) {
+        let diff = a as i16 - b as i16 - borrow as i16;
+        self.d = diff as ByteVal;
+        self.df = diff >= 0;
+    }
+
+    fn short_branch(&mut self, mem: &dyn MemoryInterface, cond: bool) {
+        let p = self.p;
+        if cond {
+            let lo = self.read(mem, p);
+            self.r[p] = (self.r[p] & 0xff00) | lo as u16;
+        } else {
+            self.r[p] = self.r[p].wrapping_add(1);
+        }
+    }
+
+    fn long_branch(&mut self, mem: &dyn MemoryInterface, cond: bool) {
+        let p = self.p;
+        if cond {
+            let hi = self.read(mem, p);
+            let lo = mem.read_byte(self.r[p].wrapping_add(1) as Addr);
+            self.r[p] = ((hi as u16) << 8) | lo as u16;
+        } else {
+            self.r[p] = self.r[p].wrapping_add(2);
+        }
+    }
+
+    fn long_skip(&mut self, cond: bool) {
+        let p = self.p;
+        if cond {
+            self.r[p] = self.r[p].wrapping_add(2);
+        }
+    }
+
+    // Execute one instruction. Returns the machine cycles taken.
+    pub fn step(&mut self, mem: &mut dyn MemoryInterface, bus: &mut dyn Cdp1802Bus) -> u32 {
+        if self.idle {
+            return 1;
+        }
+
+        let op = self.fetch_imm(mem);
+        let i = op >> 4;
+        let n = (op & 0xf) as usize;
+        let x = self.x;
+
+        match (i, n) {
+            (0x0, 0)   => self.idle = true,
+            (0x0, _)   => self.d = self.read(mem, n),
+            (0x1, _)   => self.r[n] = self.r[n].wrapping_add(1),
+            (0x2, _)   => self.r[n] = self.r[n].wrapping_sub(1),
+
+            (0x3, _)   => {
+                let cond = match n {
+                    0x0 => true,
+                    0x1 => self.q,
+                    0x2 => self.d == 0,
+                    0x3 => self.df,
+                    0x4..=0x7 => bus.ef(n as ByteVal - 0x3),
+                    0x8 => false,
+                    0x9 => !self.q,
+                    0xa => self.d != 0,
+                    0xb => !self.df,
+                    _   => !bus.ef(n as ByteVal - 0xb)
+                };
+                self.short_branch(mem, cond);
+            },
+
+            (0x4, _)   => {
+                self.d = self.read(mem, n);
+                self.r[n] = self.r[n].wrapping_add(1);
+            },
+            (0x5, _)   => mem.write_byte(self.r[n] as Addr, self.d),
+
+            (0x6, 0x0) => self.r[x] = self.r[x].wrapping_add(1),
+            (0x6, 0x8) => {},
+            (0x6, _)   => {
+                if n < 8 {
+                    let val = self.read(mem, x);
+                    bus.output(n as ByteVal, val);
+                    self.r[x] = self.r[x].wrapping_add(1);
+                } else {
+                    let val = bus.input(n as ByteVal - 8);
+                    mem.write_byte(self.r[x] as Addr, val);
+                    self.d = val;
+                }
+            },
+
+            (0x7, 0x0) | (0x7, 0x1) => {
+                let val = self.read(mem, x);
+                self.r[x] = self.r[x].wrapping_add(1);
+                self.x = (val >> 4) as usize;
+                self.p = (val & 0xf) as usize;
+                self.ie = n == 0;
+            },
+            (0x7, 0x2) => {
+                self.d = self.read(mem, x);
+                self.r[x] = self.r[x].wrapping_add(1);
+            },
+            (0x7, 0x3) => {
+                mem.write_byte(self.r[x] as Addr, self.d);
+                self.r[x] = self.r[x].wrapping_sub(1);
+            },
+            (0x7, 0x4) => { let (m, d, df) = (self.read(mem, x), self.d, self.df); self.add(m, d, df); },
+            (0x7, 0x5) => { let (m, d, df) = (self.read(mem, x), self.d, self.df); self.sub(m, d, !df); },
+            (0x7, 0x6) => {
+                let carry = self.d & 1 != 0;
+                self.d = (self.d >> 1) | if self.df { 0x80 } else { 0 };
+                self.df = carry;
+            },
+            (0x7, 0x7) => { let (m, d, df) = (self.read(mem, x), self.d, self.df); self.sub(d, m, !df); },
+            (0x7, 0x8) => mem.write_byte(self.r[x] as Addr, self.t),
+            (0x7, 0x9) => {
+                self.t = ((self.x as ByteVal) << 4) | self.p as ByteVal;
+                mem.write_byte(self.r[2] as Addr, self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            },
+            (0x7, 0xa) => self.q = false,
+            (0x7, 0xb) => self.q = true,
+            (0x7, 0xc) => { let (m, d, df) = (self.fetch_imm(mem), self.d, self.df); self.add(m, d, df); },
+            (0x7, 0xd) => { let (m, d, df) = (self.fetch_imm(mem), self.d, self.df); self.sub(m, d, !df); },
+            (0x7, 0xe) => {
+                let carry = self.d & 0x80 != 0;
+                self.d = (self.d << 1) | self.df as ByteVal;
+                self.df = carry;
+            },
+            (0x7, _)   => { let (m, d, df) = (self.fetch_imm(mem), self.d, self.df); self.sub(d, m, !df); },
+
+            (0x8, _)   => self.d = self.r[n] as ByteVal,
+            (0x9, _)   => self.d = (self.r[n] >> 8) as ByteVal,
+            (0xa, _)   => self.r[n] = (self.r[n] & 0xff00) | self.d as u16,
+            (0xb, _)   => self.r[n] = (self.r[n] & 0x00ff) | ((self.d as u16) << 8),
+
+            (0xc, _)   => {
+                match n {
+                    0x0 => self.long_branch(mem, true),
+                    0x1 => { let q = self.q; self.long_branch(mem, q) },
+                    0x2 => { let z = self.d == 0; self.long_branch(mem, z) },
+                    0x3 => { let df = self.df; self.long_branch(mem, df) },
+                    0x4 => {},
+                    0x5 => { let q = self.q; self.long_skip(!q) },
+                    0x6 => { let z = self.d == 0; self.long_skip(!z) },
+                    0x7 => { let df = self.df; self.long_skip(!df) },
+                    0x8 => self.long_skip(true),
+                    0x9 => { let q = self.q; self.long_branch(mem, !q) },
+                    0xa => { let z = self.d == 0; self.long_branch(mem, !z) },
+                    0xb => { let df = self.df; self.long_branch(mem, !df) },
+                    0xc => { let ie = self.ie; self.long_skip(ie) },
+                    0xd => { let q = self.q; self.long_skip(q) },
+                    0xe => { let z = self.d == 0; self.long_skip(z) },
+                    _   => { let df = self.df; self.long_skip(df) }
+                };
+                return 3;
+            },
+
+            (0xd, _)   => self.p = n,
+            (0xe, _)   => self.x = n,
+
+            (0xf, 0x0) => self.d = self.read(mem, x),
+            (0xf, 0x1) => self.d |= self.read(mem, x),
+            (0xf, 0x2) => self.d &= self.read(mem, x),
+            (0xf, 0x3) => self.d ^= self.read(mem, x),
+            (0xf, 0x4) => { let (m, d) = (self.read(mem, x), self.d); self.add(m, d, false); },
+            (0xf, 0x5) => { let (m, d) = (self.read(mem, x), self.d); self.sub(m, d, false); },
+            (0xf, 0x6) => {
+                self.df = self.d & 1 != 0;
+                self.d >>= 1;
+            },
+            (0xf, 0x7) => { let (m, d) = (self.read(mem, x), self.d); self.sub(d, m, false); },
+            (0xf, 0x8) => self.d = self.fetch_imm(mem),
+            (0xf, 0x9) => self.d |= self.fetch_imm(mem),
+            (0xf, 0xa) => self.d &= self.fetch_imm(mem),
+            (0xf, 0xb) => self.d ^= self.fetch_imm(mem),
+            (0xf, 0xc) => { let (m, d) = (self.fetch_imm(mem), self.d); self.add(m, d, false); },
+            (0xf, 0xd) => { let (m, d) = (self.fetch_imm(mem), self.d); self.sub(m, d, false); },
+            (0xf, 0xe) => {
+                self.df = self.d & 0x80 != 0;
+                self.d <<= 1;
+            },
+            (_, _)     => { let (m, d) = (self.fetch_imm(mem), self.d); self.sub(d, m, false); }
+        };
+
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TestMemory(Vec<ByteVal>);
+
+    impl MemoryInterface for TestMemory {
+        fn read_byte(&self, addr: Addr) -> ByteVal {
+            self.0[addr]
+        }
+
+        fn write_byte(&mut self, addr: Addr, val: ByteVal) {
+            self.0[addr] = val;
+        }
+    }
+
+    struct TestBus {
+        outputs: Vec<(ByteVal, ByteVal)>,
+        ef:      [bool; 4]
+    }
+
+    impl Cdp1802Bus for TestBus {
+        fn output(&mut self, port: ByteVal, val: ByteVal) {
+            self.outputs.push((port, val));
+        }
+
+        fn input(&mut self, port: ByteVal) -> ByteVal {
+            0xa0 | port
+        }
+
+        fn ef(&self, line: ByteVal) -> bool {
+            self.ef[line as usize - 1]
+        }
+    }
+
+    fn bus() -> TestBus {
+        TestBus { outputs: Vec::new(), ef: [false; 4] }
+    }
+
+    // Run a program loaded at 0 from reset until it reaches IDL
+    fn run_with(program: &[ByteVal], mem: &mut TestMemory, bus: &mut TestBus) -> Cdp1802 {
+        mem.0[..program.len()].copy_from_slice(program);
+        let mut cpu = Cdp1802::new();
+        for _ in 0..1000 {
+            if cpu.is_idle() {
+                return cpu;
+            }
+            cpu.step(mem, bus);
+        }
+        panic!("program did not reach IDL");
+    }
+
+    fn run(program: &[ByteVal]) -> (Cdp1802, TestMemory) {
+        let mut mem = TestMemory(vec![0; 0x100]);
+        let cpu = run_with(program, &mut mem, &mut bus());
+        (cpu, mem)
+    }
+
+    #[test]
+    fn add_sets_carry() {
+        // LDI F0, ADI 20
+        let (cpu, _) = run(&[0xf8, 0xf0, 0xfc, 0x20, 0x00]);
+        assert_eq!((cpu.d, cpu.df), (0x10, true));
+
+        // ... then ADCI 01 adds the carry in
+        let (cpu, _) = run(&[0xf8, 0xf0, 0xfc, 0x20, 0x7c, 0x01, 0x00]);
+        assert_eq!((cpu.d, cpu.df), (0x12, false));
+    }
+
+    #[test]
+    fn add_from_memory_at_rx() {
+        // LDI 40, PLO R1, SEX R1, LDI 22, ADD
+        let mut mem = TestMemory(vec![0; 0x100]);
+        mem.0[0x40] = 0x11;
+        let cpu = run_with(&[0xf8, 0x40, 0xa1, 0xe1, 0xf8, 0x22, 0xf4, 0x00], &mut mem, &mut bus());
+        assert_eq!(cpu.x, 1);
+        assert_eq!((cpu.d, cpu.df), (0x33, false));
+    }
+
+    #[test]
+    fn subtract_sets_df_without_borrow() {
+        // LDI 05, SMI 07 borrows
+        let (cpu, _) = run(&[0xf8, 0x05, 0xff, 0x07, 0x00]);
+        assert_eq!((cpu.d, cpu.df), (0xfe, false));
+
+        // LDI 05, SMI 03
+        let (cpu, _) = run(&[0xf8, 0x05, 0xff, 0x03, 0x00]);
+        assert_eq!((cpu.d, cpu.df), (0x02, true));
+
+        // LDI 03, SDI 05 subtracts D from the immediate
+        let (cpu, _) = run(&[0xf8, 0x03, 0xfd, 0x05, 0x00]);
+        assert_eq!((cpu.d, cpu.df), (0x02, true));
+
+        // LDI 05, SMI 07, SMBI 00 takes the borrow from the first
+        let (cpu, _) = run(&[0xf8, 0x05, 0xff, 0x07, 0x7f, 0x00, 0x00]);
+        assert_eq!((cpu.d, cpu.df), (0xfd, true));
+    }
+
+    #[test]
+    fn shifts_move_bits_through_df() {
+        // LDI 81, SHL, RSHL
+        let (cpu, _) = run(&[0xf8, 0x81, 0xfe, 0x00]);
+        assert_eq!((cpu.d, cpu.df), (0x02, true));
+        let (cpu, _) = run(&[0xf8, 0x81, 0xfe, 0x7e, 0x00]);
+        assert_eq!((cpu.d, cpu.df), (0x05, false));
+
+        // LDI 01, SHR, RSHR
+        let (cpu, _) = run(&[0xf8, 0x01, 0xf6, 0x00]);
+        assert_eq!((cpu.d, cpu.df), (0x00, true));
+        let (cpu, _) = run(&[0xf8, 0x01, 0xf6, 0x76, 0x00]);
+        assert_eq!((cpu.d, cpu.df), (0x80, false));
+    }
+
+    #[test]
+    fn logic_ops() {
+        // LDI 0C, ORI 30, ANI 3C, XRI FF
+        let (cpu, _) = run(&[0xf8, 0x0c, 0xf9, 0x30, 0xfa, 0x3c, 0xfb, 0xff, 0x00]);
+        assert_eq!(cpu.d, 0xc3);
+    }
+
+    #[test]
+    fn short_branches() {
+        // LDI 00, BZ 06, LDI 99
+        let (cpu, _) = run(&[0xf8, 0x00, 0x32, 0x06, 0xf8, 0x99, 0x00]);
+        assert_eq!(cpu.d, 0x00);
+
+        // LDI 01, BZ 06, LDI 99 falls through
+        let (cpu, _) = run(&[0xf8, 0x01, 0x32, 0x06, 0xf8, 0x99, 0x00]);
+        assert_eq!(cpu.d, 0x99);
+
+        // B3 06 follows EF3
+        let mut mem = TestMemory(vec![0; 0x100]);
+        let mut ef3 = bus();
+        ef3.ef[2] = true;
+        let cpu = run_with(&[0xf8, 0x01, 0x36, 0x06, 0xf8, 0x99, 0x00], &mut mem, &mut ef3);
+        assert_eq!(cpu.d, 0x01);
+    }
+
+    #[test]
+    fn long_branches_and_skips() {
+        // LBR 0010
+        let mut mem = TestMemory(vec![0; 0x100]);
+        mem.0[0x10] = 0xf8;
+        mem.0[0x11] = 0x42;
+        let cpu = run_with(&[0xc0, 0x00, 0x10, 0xf8, 0x99, 0x00], &mut mem, &mut bus());
+        assert_eq!(cpu.d, 0x42);
+        assert_eq!(cpu.r[0], 0x13);
+
+        // LDI 00, LSZ skips the LDI 99
+        let (cpu, _) = run(&[0xf8, 0x00, 0xce, 0xf8, 0x99, 0x00]);
+        assert_eq!(cpu.d, 0x00);
+
+        // LDI 01, LSZ doesn't
+        let (cpu, _) = run(&[0xf8, 0x01, 0xce, 0xf8, 0x99, 0x00]);
+        assert_eq!(cpu.d, 0x99);
+    }
+
+    #[test]
+    fn sep_changes_program_counter() {
+        // LDI 10, PLO R3, SEP R3, with LDI 55 at 10
+        let mut mem = TestMemory(vec![0; 0x100]);
+        mem.0[0x10] = 0xf8;
+        mem.0[0x11] = 0x55;
+        let cpu = run_with(&[0xf8, 0x10, 0xa3, 0xd3, 0x00], &mut mem, &mut bus());
+        assert_eq!(cpu.p, 3);
+        assert_eq!(cpu.d, 0x55);
+        assert_eq!(cpu.r[3], 0x13);
+    }
+
+    #[test]
+    fn mark_saves_x_and_p() {
+        // SEX R1, LDI 30, PLO R2, MARK
+        let (cpu, mem) = run(&[0xe1, 0xf8, 0x30, 0xa2, 0x79, 0x00]);
+        assert_eq!(mem.0[0x30], 0x10);
+        assert_eq!(cpu.x, 0);
+        assert_eq!(cpu.r[2], 0x2f);
+        assert_eq!(cpu.t, 0x10);
+    }
+
+    #[test]
+    fn ret_restores_x_and_p() {
+        // LDI 20, PLO R2, LDI 18, PLO R5, SEX R2, RET to X=3, P=5
+        let mut mem = TestMemory(vec![0; 0x100]);
+        mem.0[0x20] = 0x35;
+        let cpu = run_with(&[0xf8, 0x20, 0xa2, 0xf8, 0x18, 0xa5, 0xe2, 0x70, 0x00], &mut mem, &mut bus());
+        assert_eq!((cpu.x, cpu.p), (3, 5));
+        assert_eq!(cpu.r[2], 0x21);
+        assert_eq!(cpu.r[5], 0x19);
+        assert!(cpu.ie);
+    }
+
+    #[test]
+    fn out_and_inp_use_rx() {
+        // LDI 40, PLO R1, SEX R1, OUT 2, INP 1
+        let mut mem = TestMemory(vec![0; 0x100]);
+        mem.0[0x40] = 0x07;
+        let mut io = bus();
+        let cpu = run_with(&[0xf8, 0x40, 0xa1, 0xe1, 0x62, 0x69, 0x00], &mut mem, &mut io);
+        assert_eq!(io.outputs, vec![(2, 0x07)]);
+        assert_eq!(cpu.d, 0xa1);
+        assert_eq!(mem.0[0x41], 0xa1);
+        assert_eq!(cpu.r[1], 0x41);
+    }
+}
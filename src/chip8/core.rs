@@ -1,9 +1,15 @@
 
-use std::sync::{Arc, Mutex};
+use std::cell::RefCell;
+use std::rc::Rc;
 use chip8::types::{Addr, ByteVal, RegNum, TimerVal};
+use chip8::cdp1802::{Cdp1802, Cdp1802Bus};
+use chip8::variant::MachineVariant;
+use chip8::quirks::Quirks;
+use chip8::font::{FONT_GLYPH_SIZE, BIG_FONT_ADDR, BIG_FONT_GLYPH_SIZE};
+use chip8::megachip::{MegaChipRegs, MegaFrameBuffer, MegaSample, BlendMode};
 
-#[derive(Debug)]
-pub struct OpVal(ByteVal, ByteVal, ByteVal, ByteVal);
+#[derive(Debug, Clone, Copy)]
+pub struct OpVal(pub ByteVal, pub ByteVal, pub ByteVal, pub ByteVal);
 
 pub trait MemoryInterface {
     fn read_byte(&self, Addr) -> ByteVal;
@@ -12,7 +18,12 @@ pub trait MemoryInterface {
 
 pub trait DisplayInterface {
     fn dimensions(&self) -> (usize, usize);
+    fn set_dimensions(&mut self, width: usize, height: usize);
     fn clear(&mut self);
+
+    // Bitplanes affected by clearing, drawing and scrolling
+    fn select_planes(&mut self, planes: ByteVal);
+    fn scroll(&mut self, dx: isize, dy: isize);
     
     fn read_pixel(&self, x: ByteVal, y: ByteVal) -> ByteVal;
     
@@ -21,18 +32,96 @@ pub trait DisplayInterface {
 
     fn write_pixel_row(&mut self, x: ByteVal, y : ByteVal, rowval: ByteVal);
     fn write_pixel_row_xor(&mut self, x: ByteVal, y : ByteVal, rowval: ByteVal) -> bool;
+
+    // XOR up to 16 sprite pixels, left-aligned in `bits`, into row y from x
+    // onwards, returning true if a lit pixel was cleared. Pixels past the
+    // right edge wrap around or are clipped.
+    fn xor_sprite_row(&mut self, x: usize, y: usize, bits: u16, wrap: bool) -> bool {
+        let width = self.dimensions().0;
+        let mut cleared = false;
+        for bit in 0..16 {
+            let px = x + bit;
+            if px >= width && !wrap {
+                break;
+            }
+            if bits & (0x8000 >> bit) != 0 && self.write_pixel_xor((px % width) as ByteVal, y as ByteVal, 1) {
+                cleared = true;
+            }
+        }
+        cleared
+    }
+
+    // CHIP-8X colour attributes. Colours are VP-590 palette indices, and are
+    // None when colour is not enabled.
+    fn set_colour_enabled(&mut self, enabled: bool);
+    fn cycle_background_colour(&mut self);
+    fn set_zone_colour(&mut self, x: usize, y: usize, width: usize, height: usize, val: ByteVal);
+    fn background_colour(&self) -> Option<ByteVal>;
+    fn pixel_colour(&self, x: ByteVal, y: ByteVal) -> Option<ByteVal>;
+
+    // MegaChip mode swaps in a 256x192 colour framebuffer
+    fn set_megachip_enabled(&mut self, enabled: bool);
+    fn megachip(&mut self) -> Option<&mut MegaFrameBuffer>;
+
+    // Called at the end of each 60Hz frame to publish what has been drawn.
+    // Returns true if there is a new frame to show.
+    fn vblank(&mut self) -> bool;
+}
+
+pub type RcRefDisplayInterface = Rc<RefCell<DisplayInterface>>;
+
+pub trait AudioInterface {
+    fn play_sample(&mut self, sample: MegaSample, looped: bool);
+    fn stop_sample(&mut self);
 }
 
-pub type RcRefDisplayInterface = Arc<Mutex<DisplayInterface + Send>>;
+pub type RcRefAudioInterface = Rc<RefCell<AudioInterface>>;
 
 pub trait KeyboardInterface {
     fn key_pressed(&self, key: ByteVal) -> bool;
-    fn wait_for_key(&self) -> ByteVal;
+    // Keys on the CHIP-8X second keypad
+    fn second_key_pressed(&self, key: ByteVal) -> bool;
+    // Record that the program tested a key, or waited for any key if None
+    fn key_polled(&mut self, key: Option<ByteVal>);
 }
 
-pub type RcRefKeyboardInterface = Arc<Mutex<KeyboardInterface + Send>>;
+pub type RcRefKeyboardInterface = Rc<RefCell<KeyboardInterface>>;
                  
-pub const PROG_START_ADDR: Addr = 0x200;
+// Sprites below here are font glyphs, which MegaChip draws as plain sprites
+const FONT_END_ADDR: Addr = 0x100;
+
+// Layout used by the original COSMAC VIP interpreter in a 4K machine, which
+// machine code subroutines expect to find
+const VIP_VREG_ADDR: Addr = 0xef0;
+const VIP_STACK_ADDR: u16 = 0xecf;
+const VIP_DISPLAY_ADDR: Addr = 0xf00;
+const VIP_DISPLAY_PAGE: u16 = 0x0f;
+
+// Upper bound on 1802 instructions executed by one 0NNN call
+const VIP_MAX_MACHINE_STEPS: usize = 1_000_000;
+
+// Hex keypad as seen by machine code: OUT 2 latches a key and EF3 reports
+// whether it is pressed
+struct VipKeypadBus<'a> {
+    keyboard: &'a RcRefKeyboardInterface,
+    latch:    ByteVal
+}
+
+impl<'a> Cdp1802Bus for VipKeypadBus<'a> {
+    fn output(&mut self, port: ByteVal, val: ByteVal) {
+        if port == 2 {
+            self.latch = val & 0xf;
+        }
+    }
+
+    fn input(&mut self, _port: ByteVal) -> ByteVal {
+        0
+    }
+
+    fn ef(&self, line: ByteVal) -> bool {
+        line == 3 && self.keyboard.borrow().key_pressed(self.latch)
+    }
+}
 
 pub struct CPU<'a> {
     pc:    Addr,
@@ -41,43 +130,107 @@ pub struct CPU<'a> {
     dt:    TimerVal,
     st:    TimerVal,
     stack: Vec<Addr>,
+
+    variant: MachineVariant,
+    quirks:  Quirks,
+    halted:  bool,
+    rpl:     [ByteVal; 16],
+    planes:  ByteVal,
+    audio_pattern: [ByteVal; 16],
+    pitch:   ByteVal,
+    cdp1802: Option<Cdp1802>,
+    io_port: ByteVal,
+    mega:    MegaChipRegs,
+    mega_enabled: bool,
         
     mem:      &'a mut MemoryInterface,
     display:  &'a RcRefDisplayInterface,
-    keyboard: &'a RcRefKeyboardInterface
+    keyboard: &'a RcRefKeyboardInterface,
+    audio:    &'a RcRefAudioInterface
 }
 
 impl<'a> CPU<'a> {
     pub fn new(mem: &'a mut MemoryInterface,
                display: &'a RcRefDisplayInterface,
-               keyboard: &'a RcRefKeyboardInterface) -> Self {
+               keyboard: &'a RcRefKeyboardInterface,
+               audio: &'a RcRefAudioInterface,
+               variant: MachineVariant) -> Self {
         CPU {
-            pc:    PROG_START_ADDR,
+            pc:    variant.start_addr(),
             vreg:  [0; 16],
             ireg:  0,
             dt:    0,
             st:    0,
             stack: Vec::new(),
 
+            variant: variant,
+            quirks:  variant.quirks(),
+            halted:  false,
+            rpl:     [0; 16],
+            planes:  1,
+            audio_pattern: [0; 16],
+            pitch:   64,
+            cdp1802: None,
+            io_port: 0,
+            mega:    MegaChipRegs::new(),
+            mega_enabled: false,
+
             mem:      mem,
             display:  display,
-            keyboard: keyboard
+            keyboard: keyboard,
+            audio:    audio
         }
     }
 
     pub fn dump_reg(&self) {
-        print!("V:[");
+        eprint!("V:[");
         for i in 0..16 {
-            print!("{}:{:x?} ", i, self.vreg[i as usize]);
+            eprint!("{}:{:x?} ", i, self.vreg[i as usize]);
         }
-        print!("] ");
+        eprint!("] ");
         
-        print!("I:{:x?},{} ", self.ireg, self.ireg);
+        eprint!("I:{:x?},{} ", self.ireg, self.ireg);
 
-        println!("");
+        eprintln!("");
         
     }
 
+    // Run 0NNN calls as RCA 1802 machine code, as on the COSMAC VIP
+    pub fn set_vip_hybrid(&mut self, enabled: bool) {
+        self.cdp1802 = if enabled { Some(Cdp1802::new()) } else { None };
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    // True once a SUPER-CHIP program has exited with 00FD
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
+
+    // Publish the frame drawn so far
+    pub fn vblank(&mut self) {
+        self.display.borrow_mut().vblank();
+    }
+
+    // XO-CHIP audio pattern buffer and playback pitch
+    pub fn audio_pattern(&self) -> (&[ByteVal; 16], ByteVal) {
+        (&self.audio_pattern, self.pitch)
+    }
+
+    pub fn pc(&self) -> Addr {
+        self.pc
+    }
+
+    pub fn vreg(&self, vx: RegNum) -> ByteVal {
+        self.vreg[vx]
+    }
+
     fn set_pc(&mut self, addr: Addr) {
         self.pc = addr;
     }
@@ -86,6 +239,14 @@ impl<'a> CPU<'a> {
         self.pc += 2;
     }
 
+    // Skip the next instruction, which on XO-CHIP may be the four byte F000
+    fn skip(&mut self) {
+        if self.variant.has_xochip_ops() && self.mem.read_byte(self.pc) == 0xf0 && self.mem.read_byte(self.pc + 1) == 0x00 {
+            self.incr_pc();
+        }
+        self.incr_pc();
+    }
+
     pub fn fetch_op(&mut self) -> (OpVal, Addr) {
         let b0 = self.mem.read_byte(self.pc);
         let b1 = self.mem.read_byte(self.pc + 1);
@@ -109,6 +270,27 @@ impl<'a> CPU<'a> {
             (0x0, 0x0, 0x0, 0x0) => self.op_undef(),
             (0x0, 0x0, 0xe, 0x0) => self.op_cls(),
             (0x0, 0x0, 0xe, 0xe) => self.op_ret(),
+            (0x0, 0x2, 0x3, 0x0) if self.variant == MachineVariant::HiRes => self.op_cls(),
+            (0x0, 0x2, 0xa, 0x0) if self.variant == MachineVariant::Chip8X => self.op_cycle_bg(),
+            (0x0, 0x0, 0x1, 0x0) if self.variant == MachineVariant::MegaChip => self.op_megachip(false),
+            (0x0, 0x0, 0x1, 0x1) if self.variant == MachineVariant::MegaChip => self.op_megachip(true),
+            (0x0, 0x0, 0xb,   _) if self.mega_enabled => self.op_scroll(0, -(imm4 as isize)),
+            (0x0, 0x0, 0xc,   _) if self.variant.has_scroll_ops() => self.op_scroll(0, imm4 as isize),
+            (0x0, 0x0, 0xd,   _) if self.variant.has_xochip_ops() => self.op_scroll(0, -(imm4 as isize)),
+            (0x0, 0x0, 0xf, 0xb) if self.variant.has_scroll_ops() => self.op_scroll(4, 0),
+            (0x0, 0x0, 0xf, 0xc) if self.variant.has_scroll_ops() => self.op_scroll(-4, 0),
+            (0x0, 0x0, 0xf, 0xd) if self.variant.has_schip_ops() => self.op_exit(),
+            (0x0, 0x0, 0xf, 0xe) if self.variant.has_schip_ops() => self.op_set_hires(false),
+            (0x0, 0x0, 0xf, 0xf) if self.variant.has_schip_ops() => self.op_set_hires(true),
+            (0x0, 0x1,   _,   _) if self.mega_enabled => self.op_ldi_long(imm8),
+            (0x0, 0x2,   _,   _) if self.mega_enabled => self.op_ldpal(imm8),
+            (0x0, 0x3,   _,   _) if self.mega_enabled => self.mega.sprite_width = if imm8 == 0 { 256 } else { imm8 as usize },
+            (0x0, 0x4,   _,   _) if self.mega_enabled => self.mega.sprite_height = if imm8 == 0 { 256 } else { imm8 as usize },
+            (0x0, 0x5,   _,   _) if self.mega_enabled => self.mega.alpha = imm8,
+            (0x0, 0x6, 0x0,   _) if self.mega_enabled => self.op_play_sample(imm4 == 0),
+            (0x0, 0x7, 0x0, 0x0) if self.mega_enabled => self.op_stop_sample(),
+            (0x0, 0x8, 0x0,   _) if self.mega_enabled => self.mega.blend = BlendMode::from_value(imm4),
+            (0x0, 0x9,   _,   _) if self.mega_enabled => self.mega.collision_index = imm8,
             (0x0,   _,   _,   _) => self.op_sys(addr),
             
             (0x1,   _,   _,   _) => self.op_jp(addr),
@@ -116,6 +298,9 @@ impl<'a> CPU<'a> {
             (0x3,   _,   _,   _) => self.op_sec(x, imm8),
             (0x4,   _,   _,   _) => self.op_snec(x, imm8),
             (0x5,   _,   _, 0x0) => self.op_se(x, y),
+            (0x5,   _,   _, 0x1) if self.variant == MachineVariant::Chip8X => self.op_add_nibbles(x, y),
+            (0x5,   _,   _, 0x2) if self.variant.has_xochip_ops() => self.op_strange(x, y),
+            (0x5,   _,   _, 0x3) if self.variant.has_xochip_ops() => self.op_ldrange(x, y),
             (0x6,   _,   _,   _) => self.op_ldc(x, imm8),
             (0x7,   _,   _,   _) => self.op_addc(x, imm8),
 
@@ -126,29 +311,44 @@ impl<'a> CPU<'a> {
             (0x8,   _,   _, 0x3) => self.op_xor(x, y),
             (0x8,   _,   _, 0x4) => self.op_add(x, y),
             (0x8,   _,   _, 0x5) => self.op_sub(x, y),
-            (0x8,   _,   _, 0x6) => self.op_shr(x),
+            (0x8,   _,   _, 0x6) => self.op_shr(x, y),
             (0x8,   _,   _, 0x7) => self.op_subn(x, y),
-            (0x8,   _,   _, 0xe) => self.op_shl(x),
+            (0x8,   _,   _, 0xe) => self.op_shl(x, y),
 
             (0x9,   _,   _, 0x0) => self.op_sne(x, y),
 
             (0xa,   _,   _,   _) => self.op_ldi(addr),
+            (0xb,   _,   _, 0x0) if self.variant == MachineVariant::Chip8X => self.op_colour_zones(x, y),
+            (0xb,   _,   _,   _) if self.variant == MachineVariant::Chip8X => self.op_colour_rows(x, y, imm4),
             (0xb,   _,   _,   _) => self.op_jp_rel(addr),
             (0xc,   _,   _,   _) => self.op_rnd(x, imm8),
+            (0xd,   _,   _,   _) if self.mega_enabled && self.ireg >= FONT_END_ADDR => self.op_drw_mega(x, y),
+            (0xd,   _,   _, 0x0) if self.variant.has_schip_ops() => self.op_drw_big(x, y),
             (0xd,   _,   _,   _) => self.op_drw(x, y, imm4),
             
             (0xe,   _, 0x9, 0xe) => self.op_skp(x),
             (0xe,   _, 0xa, 0x1) => self.op_sknp(x),
+            (0xe,   _, 0xf, 0x2) if self.variant == MachineVariant::Chip8X => self.op_skp2(x),
+            (0xe,   _, 0xf, 0x5) if self.variant == MachineVariant::Chip8X => self.op_sknp2(x),
 
+            (0xf, 0x0, 0x0, 0x0) if self.variant.has_xochip_ops() => self.op_ldi_xo(),
+            (0xf,   _, 0x0, 0x1) if self.variant.has_xochip_ops() => self.op_planes(n1),
+            (0xf, 0x0, 0x0, 0x2) if self.variant.has_xochip_ops() => self.op_ldaudio(),
             (0xf,   _, 0x0, 0x7) => self.op_lddt(x),
             (0xf,   _, 0x0, 0xa) => self.op_ldtc(x),
             (0xf,   _, 0x1, 0x5) => self.op_stdt(x),
             (0xf,   _, 0x1, 0x8) => self.op_stst(x),
             (0xf,   _, 0x1, 0xe) => self.op_addi(x),
             (0xf,   _, 0x2, 0x9) => self.op_ldsprt(x),
+            (0xf,   _, 0x3, 0x0) if self.variant.has_schip_ops() => self.op_ldbigsprt(x),
             (0xf,   _, 0x3, 0x3) => self.op_stbcd(x),
+            (0xf,   _, 0x3, 0xa) if self.variant.has_xochip_ops() => self.op_pitch(x),
             (0xf,   _, 0x5, 0x5) => self.op_stall(x),
             (0xf,   _, 0x6, 0x5) => self.op_ldall(x),
+            (0xf,   _, 0x7, 0x5) if self.variant.has_schip_ops() => self.op_strpl(x),
+            (0xf,   _, 0x8, 0x5) if self.variant.has_schip_ops() => self.op_ldrpl(x),
+            (0xf,   _, 0xf, 0x8) if self.variant == MachineVariant::Chip8X => self.op_out(x),
+            (0xf,   _, 0xf, 0xb) if self.variant == MachineVariant::Chip8X => self.op_in(x),
 
             (  _,   _,   _,   _) => self.op_undef()
         };
@@ -156,12 +356,81 @@ impl<'a> CPU<'a> {
 
     // Call RCA 1802 program at give address
     fn op_sys(&mut self, addr: Addr) {
-        eprintln!("RCA1802 calls are not supported!");
+        match self.cdp1802.take() {
+            Some(mut cdp1802) => {
+                self.call_machine_code(&mut cdp1802, addr);
+                self.cdp1802 = Some(cdp1802);
+            },
+            None              => eprintln!("RCA1802 calls are not supported!")
+        };
+    }
+
+    // Set up memory and the 1802 registers the way the VIP interpreter
+    // leaves them, run the routine until it returns to the interpreter with
+    // SEP R4, then pick up any changes it made
+    fn call_machine_code(&mut self, cdp1802: &mut Cdp1802, addr: Addr) {
+        for i in 0..16 {
+            self.mem.write_byte(VIP_VREG_ADDR + i, self.vreg[i]);
+        }
+        self.copy_display_to_memory();
+
+        cdp1802.r[2] = VIP_STACK_ADDR;
+        cdp1802.r[3] = addr as u16;
+        cdp1802.r[5] = self.pc as u16;
+        cdp1802.r[6] = (VIP_VREG_ADDR + ((addr >> 8) & 0xf)) as u16;
+        cdp1802.r[7] = (VIP_VREG_ADDR + ((addr >> 4) & 0xf)) as u16;
+        cdp1802.r[8] = ((self.dt as u16) << 8) | self.st as u16;
+        cdp1802.r[0xa] = self.ireg as u16;
+        cdp1802.r[0xb] = VIP_DISPLAY_PAGE << 8;
+        cdp1802.p = 3;
+        cdp1802.x = 2;
+
+        let mut bus = VipKeypadBus { keyboard: self.keyboard, latch: 0 };
+        let mut steps = 0;
+        while cdp1802.p != 4 {
+            if steps == VIP_MAX_MACHINE_STEPS || cdp1802.is_idle() {
+                eprintln!("RCA1802 routine at {:x} did not return", addr);
+                break;
+            }
+            cdp1802.step(self.mem, &mut bus);
+            steps += 1;
+        }
+
+        for i in 0..16 {
+            self.vreg[i] = self.mem.read_byte(VIP_VREG_ADDR + i);
+        }
+        self.copy_display_from_memory();
+
+        self.pc = cdp1802.r[5] as Addr;
+        self.ireg = cdp1802.r[0xa] as Addr;
+        self.dt = (cdp1802.r[8] >> 8) as TimerVal;
+        self.st = cdp1802.r[8] as TimerVal;
+    }
+
+    // The VIP keeps its 64x32 display as 256 bytes at the top of memory
+    fn copy_display_to_memory(&mut self) {
+        let display = self.display.borrow();
+        for off in 0..256 {
+            let (x, y) = ((off % 8) * 8, off / 8);
+            let mut rowval = 0;
+            for i in 0..8 {
+                rowval = (rowval << 1) | ((*display).read_pixel((x + i) as ByteVal, y as ByteVal) & 1);
+            }
+            self.mem.write_byte(VIP_DISPLAY_ADDR + off, rowval);
+        }
+    }
+
+    fn copy_display_from_memory(&mut self) {
+        let mut display = self.display.borrow_mut();
+        for off in 0..256 {
+            let (x, y) = ((off % 8) * 8, off / 8);
+            (*display).write_pixel_row(x as ByteVal, y as ByteVal, self.mem.read_byte(VIP_DISPLAY_ADDR + off));
+        }
     }
 
     // Clear the display
     fn op_cls(&mut self) {
-        let mut display = self.display.lock().unwrap();
+        let mut display = self.display.borrow_mut();
         (*display).clear();
     }
 
@@ -178,9 +447,10 @@ impl<'a> CPU<'a> {
         self.pc = addr;
     }
 
-    // Jump to V0 + addr
+    // Jump to V0 + addr, or VX + addr where X is the top nibble of addr
     fn op_jp_rel(&mut self, addr: Addr) {
-        self.pc = self.vreg[0] as Addr + addr;
+        let vx = if self.quirks.jump_uses_vx { (addr >> 8) & 0xf } else { 0 };
+        self.pc = self.vreg[vx] as Addr + addr;
     }
 
     // Call subroutine at addr
@@ -192,28 +462,28 @@ impl<'a> CPU<'a> {
     // Skip next instruction if reg == val
     fn op_sec(&mut self, vx: RegNum, val: ByteVal) {
         if self.vreg[vx] == val {
-            self.incr_pc();
+            self.skip();
         }
     }
 
     // Skip next instruction if reg != val
     fn op_snec(&mut self, vx: RegNum, val: ByteVal) {
         if self.vreg[vx] != val {
-            self.incr_pc();
+            self.skip();
         }
     }
 
     // Skip next instruction if reg1 == reg2
     fn op_se(&mut self, vx: RegNum, vy: RegNum) {
         if self.vreg[vx] == self.vreg[vy] {
-            self.incr_pc();
+            self.skip();
         }
     }
 
     // Skip next instruction if reg1 != reg2
     fn op_sne(&mut self, vx: RegNum, vy: RegNum) {
         if self.vreg[vx] != self.vreg[vy] {
-            self.incr_pc();
+            self.skip();
         }
     }
 
@@ -247,26 +517,23 @@ impl<'a> CPU<'a> {
         self.st = self.vreg[vx] as TimerVal;
     }
 
-    // Wait for key and place key in reg
+    // Wait for key and place key in reg. Until one is pressed the
+    // instruction runs again each step, so frames and timers carry on.
     fn op_ldtc(&mut self, vx: RegNum) {
-        /*
-        let keyboard = self.keyboard.lock().unwrap();
-        self.vreg[vx] = keyboard.wait_for_key();
-         */
-        'running: loop {
-            let keyboard = self.keyboard.lock().unwrap();
-            for i in 0..16 {
-                if keyboard.key_pressed(i) {
-                    self.vreg[vx] = i as ByteVal;
-                    break 'running;
-                }
-            }
+        self.keyboard.borrow_mut().key_polled(None);
+        let pressed = {
+            let keyboard = self.keyboard.borrow();
+            (0..16).find(|&i| keyboard.key_pressed(i))
+        };
+        match pressed {
+            Some(key) => self.vreg[vx] = key,
+            None      => self.pc -= 2
         }
     }
 
     // Load IREG with sprite address of character in vx
     fn op_ldsprt(&mut self, vx: RegNum) {
-        self.ireg = self.vreg[vx] as Addr * 5;
+        self.ireg = (self.vreg[vx] & 0xf) as Addr * FONT_GLYPH_SIZE;
     }
 
     // Store BCD representation of value in vx to [IREG], [IREG+1] and [IREG+2]
@@ -282,6 +549,9 @@ impl<'a> CPU<'a> {
         for i in 0..=vx {
             self.vreg[i] = self.mem.read_byte(self.ireg + i);
         }
+        if self.quirks.load_store_increments_i {
+            self.ireg += vx + 1;
+        }
     }
 
     //Store registers v0-vx to [i]
@@ -289,33 +559,41 @@ impl<'a> CPU<'a> {
         for i in 0..=vx {
             self.mem.write_byte(self.ireg + i, self.vreg[i]);
         }
+        if self.quirks.load_store_increments_i {
+            self.ireg += vx + 1;
+        }
     }
 
     // vx <- vx | vy
     fn op_or(&mut self, vx: RegNum, vy: RegNum) {
         self.vreg[vx] |= self.vreg[vy];
+        if self.quirks.vf_reset { self.vreg[0xf] = 0; }
     }
 
     // vx <- vx & vy
     fn op_and(&mut self, vx: RegNum, vy: RegNum) {
         self.vreg[vx] &= self.vreg[vy];
+        if self.quirks.vf_reset { self.vreg[0xf] = 0; }
     }
 
     // vx <- vx & vy
     fn op_xor(&mut self, vx: RegNum, vy: RegNum) {
         self.vreg[vx] ^= self.vreg[vy];
+        if self.quirks.vf_reset { self.vreg[0xf] = 0; }
     }
 
-    // vx <- vx >> 1
-    fn op_shr(&mut self, vx: RegNum) {
-        self.vreg[0xf] = self.vreg[vx] & 1;
-        self.vreg[vx] >>= 1;
+    // vx <- vx >> 1, or vx <- vy >> 1
+    fn op_shr(&mut self, vx: RegNum, vy: RegNum) {
+        let val = if self.quirks.shift_uses_vy { self.vreg[vy] } else { self.vreg[vx] };
+        self.vreg[vx] = val >> 1;
+        self.vreg[0xf] = val & 1;
     }
 
-    // vx <- vx << 1
-    fn op_shl(&mut self, vx: RegNum) {
-        self.vreg[0xf] = self.vreg[vx] >> 7;
-        self.vreg[vx] <<= 1;
+    // vx <- vx << 1, or vx <- vy << 1
+    fn op_shl(&mut self, vx: RegNum, vy: RegNum) {
+        let val = if self.quirks.shift_uses_vy { self.vreg[vy] } else { self.vreg[vx] };
+        self.vreg[vx] = val << 1;
+        self.vreg[0xf] = val >> 7;
     }
 
     // vx <- vx + val
@@ -350,38 +628,273 @@ impl<'a> CPU<'a> {
         eprintln!("rnd not implemented");
     }
 
-    // Draw
+    // Draw an 8 pixel wide sprite of val rows
     fn op_drw(&mut self, vx: RegNum, vy: RegNum, val: ByteVal) {
-        let x = self.vreg[vx];
-        let y = self.vreg[vy];
+        self.draw_sprite(vx, vy, val as usize, 1);
+    }
+
+    // Draw a 16x16 sprite, stored as two bytes per row
+    fn op_drw_big(&mut self, vx: RegNum, vy: RegNum) {
+        self.draw_sprite(vx, vy, 16, 2);
+    }
+
+    // Read a sprite row, left-aligned in 16 bits
+    fn sprite_row(&self, addr: Addr, row_bytes: usize) -> u16 {
+        let hi = (self.mem.read_byte(addr) as u16) << 8;
+        if row_bytes == 2 { hi | self.mem.read_byte(addr + 1) as u16 } else { hi }
+    }
+
+    // The start position wraps around the display; the rest of the sprite
+    // clips or wraps depending on quirks. With XO-CHIP each selected plane
+    // takes the next sprite from [IREG].
+    fn draw_sprite(&mut self, vx: RegNum, vy: RegNum, height: usize, row_bytes: usize) {
+        let mut display = self.display.borrow_mut();
+        let (display_width, display_height) = (*display).dimensions();
+        let x = self.vreg[vx] as usize % display_width;
+        let y = self.vreg[vy] as usize % display_height;
 
         self.vreg[0xf] = 0;
 
-        let mut display = self.display.lock().unwrap();
-        
-        for i in 0..val {
-            let rowval = self.mem.read_byte(self.ireg + i as Addr);
-            let cleared = (*display).write_pixel_row_xor(x, y + i, rowval);
-            if cleared { self.vreg[0xf] = 1; }
+        let mut addr = self.ireg;
+        for plane in 0..8 {
+            let mask = 1 << plane;
+            if self.planes & mask == 0 {
+                continue;
+            }
+
+            (*display).select_planes(mask);
+            for i in 0..height {
+                let py = y + i;
+                if py >= display_height && !self.quirks.wrap_sprites {
+                    break;
+                }
+                let row = self.sprite_row(addr + i * row_bytes, row_bytes);
+                if (*display).xor_sprite_row(x, py % display_height, row, self.quirks.wrap_sprites) {
+                    self.vreg[0xf] = 1;
+                }
+            }
+            addr += height * row_bytes;
         }
+        (*display).select_planes(self.planes);
     }
 
     // Skip next instruction if key specified in reg is pressed
     fn op_skp(&mut self, vx: RegNum) {
-        let keyboard = self.keyboard.lock().unwrap();
+        let mut keyboard = self.keyboard.borrow_mut();
+        (*keyboard).key_polled(Some(self.vreg[vx]));
         if (*keyboard).key_pressed(self.vreg[vx]) {
-            self.incr_pc();
+            self.skip();
         }
     }
 
     // Skip next instruction if key specified in reg is not pressed
     fn op_sknp(&mut self, vx: RegNum) {
-        let keyboard = self.keyboard.lock().unwrap();
+        let mut keyboard = self.keyboard.borrow_mut();
+        (*keyboard).key_polled(Some(self.vreg[vx]));
         if !(*keyboard).key_pressed(self.vreg[vx]) {
-            self.incr_pc();
+            self.skip();
+        }
+    }
+
+    // Cycle the CHIP-8X background colour
+    fn op_cycle_bg(&mut self) {
+        let mut display = self.display.borrow_mut();
+        (*display).cycle_background_colour();
+    }
+
+    // vx <- vx + vy, adding each nibble separately modulo 8
+    fn op_add_nibbles(&mut self, vx: RegNum, vy: RegNum) {
+        let (a, b) = (self.vreg[vx], self.vreg[vy]);
+        self.vreg[vx] = ((a & 0x70) + (b & 0x70)) & 0x70 | ((a & 0x7) + (b & 0x7)) & 0x7;
+    }
+
+    // Colour 8x4 zones. vx holds the left zone in its low nibble and the
+    // extra width in its high nibble, vx+1 the same for the vertical.
+    fn op_colour_zones(&mut self, vx: RegNum, vy: RegNum) {
+        let (h, v) = (self.vreg[vx] as usize, self.vreg[(vx + 1) & 0xf] as usize);
+        let colour = self.vreg[vy] & 0x7;
+
+        let mut display = self.display.borrow_mut();
+        (*display).set_zone_colour((h & 0xf) * 8, (v & 0xf) * 4, ((h >> 4) + 1) * 8, ((v >> 4) + 1) * 4, colour);
+    }
+
+    // Colour n rows of the zone containing (vx, vx+1)
+    fn op_colour_rows(&mut self, vx: RegNum, vy: RegNum, val: ByteVal) {
+        let (x, y) = (self.vreg[vx] as usize, self.vreg[(vx + 1) & 0xf] as usize);
+        let colour = self.vreg[vy] & 0x7;
+
+        let mut display = self.display.borrow_mut();
+        (*display).set_zone_colour(x, y, 1, val as usize, colour);
+    }
+
+    // Skip next instruction if key specified in reg is pressed on keypad 2
+    fn op_skp2(&mut self, vx: RegNum) {
+        let keyboard = self.keyboard.borrow();
+        if (*keyboard).second_key_pressed(self.vreg[vx]) {
+            self.skip();
+        }
+    }
+
+    // Skip next instruction if key specified in reg is not pressed on keypad 2
+    fn op_sknp2(&mut self, vx: RegNum) {
+        let keyboard = self.keyboard.borrow();
+        if !(*keyboard).second_key_pressed(self.vreg[vx]) {
+            self.skip();
+        }
+    }
+
+    // Output vx to the I/O port
+    fn op_out(&mut self, vx: RegNum) {
+        self.io_port = self.vreg[vx];
+    }
+
+    // Input from the I/O port to vx. Nothing is attached, so the last
+    // output value is read back.
+    fn op_in(&mut self, vx: RegNum) {
+        self.vreg[vx] = self.io_port;
+    }
+
+    // Switch MegaChip mode on or off
+    fn op_megachip(&mut self, enabled: bool) {
+        self.mega_enabled = enabled;
+        let mut display = self.display.borrow_mut();
+        (*display).set_megachip_enabled(enabled);
+    }
+
+    // Load IREG with the 24-bit address made from nn and the next word
+    fn op_ldi_long(&mut self, val: ByteVal) {
+        let lo = ((self.mem.read_byte(self.pc) as Addr) << 8) | self.mem.read_byte(self.pc + 1) as Addr;
+        self.ireg = ((val as Addr) << 16) | lo;
+        self.incr_pc();
+    }
+
+    // Up to len bytes from addr, stopping at the end of memory
+    fn read_bytes(&self, addr: Addr, len: usize) -> Vec<ByteVal> {
+        let len = len.min(self.variant.memory_size().saturating_sub(addr));
+        (0..len).map(|i| self.mem.read_byte(addr + i)).collect()
+    }
+
+    // Load val ARGB palette entries from [IREG]
+    fn op_ldpal(&mut self, val: ByteVal) {
+        let colours: Vec<u32> = self.read_bytes(self.ireg, val as usize * 4).chunks(4).map(|argb| {
+            argb.iter().fold(0, |colour, &b| (colour << 8) | b as u32)
+        }).collect();
+
+        let mut display = self.display.borrow_mut();
+        if let Some(mega) = (*display).megachip() {
+            mega.load_palette(&colours);
+        }
+    }
+
+    fn op_play_sample(&mut self, looped: bool) {
+        let rate = ((self.mem.read_byte(self.ireg) as u32) << 8) | self.mem.read_byte(self.ireg + 1) as u32;
+        let len = (0..3).fold(0, |len, b| (len << 8) | self.mem.read_byte(self.ireg + 2 + b) as Addr);
+        let samples = self.read_bytes(self.ireg + 6, len);
+
+        let mut audio = self.audio.borrow_mut();
+        (*audio).play_sample(MegaSample { rate: rate, samples: samples }, looped);
+    }
+
+    fn op_stop_sample(&mut self) {
+        let mut audio = self.audio.borrow_mut();
+        (*audio).stop_sample();
+    }
+
+    // Draw a sprite of palette indices, sized by the sprite width and
+    // height registers
+    fn op_drw_mega(&mut self, vx: RegNum, vy: RegNum) {
+        let (x, y) = (self.vreg[vx] as usize, self.vreg[vy] as usize);
+        let (width, height) = (self.mega.sprite_width, self.mega.sprite_height);
+        let data = self.read_bytes(self.ireg, width * height);
+
+        let mut display = self.display.borrow_mut();
+        let collided = match (*display).megachip() {
+            Some(mega) => mega.draw_sprite(x, y, width, height, &data, &self.mega),
+            None       => false
+        };
+        self.vreg[0xf] = collided as ByteVal;
+    }
+
+    // Scroll the display by whole pixels at either resolution. SUPER-CHIP 1.1
+    // on the HP-48 scrolls by half a pixel in low resolution, which isn't
+    // emulated.
+    fn op_scroll(&mut self, dx: isize, dy: isize) {
+        let mut display = self.display.borrow_mut();
+        (*display).scroll(dx, dy);
+    }
+
+    // Exit the interpreter
+    fn op_exit(&mut self) {
+        self.halted = true;
+    }
+
+    // Switch between the low and high resolution displays
+    fn op_set_hires(&mut self, hires: bool) {
+        let (width, height) = if hires { self.variant.hires_display_size() } else { self.variant.display_size() };
+        let mut display = self.display.borrow_mut();
+        (*display).set_dimensions(width, height);
+    }
+
+    // Load IREG with big sprite address of the digit in vx
+    fn op_ldbigsprt(&mut self, vx: RegNum) {
+        self.ireg = BIG_FONT_ADDR + (self.vreg[vx] % 10) as Addr * BIG_FONT_GLYPH_SIZE;
+    }
+
+    // Store v0-vx in the HP-48 RPL user flags
+    fn op_strpl(&mut self, vx: RegNum) {
+        for i in 0..=vx {
+            self.rpl[i] = self.vreg[i];
+        }
+    }
+
+    // Load v0-vx from the HP-48 RPL user flags
+    fn op_ldrpl(&mut self, vx: RegNum) {
+        for i in 0..=vx {
+            self.vreg[i] = self.rpl[i];
+        }
+    }
+
+    // Store vx..vy to [i], in either order, without changing IREG
+    fn op_strange(&mut self, vx: RegNum, vy: RegNum) {
+        let regs: Vec<RegNum> = if vx <= vy { (vx..=vy).collect() } else { (vy..=vx).rev().collect() };
+        for (i, reg) in regs.into_iter().enumerate() {
+            self.mem.write_byte(self.ireg + i, self.vreg[reg]);
+        }
+    }
+
+    // Load vx..vy from [i], in either order, without changing IREG
+    fn op_ldrange(&mut self, vx: RegNum, vy: RegNum) {
+        let regs: Vec<RegNum> = if vx <= vy { (vx..=vy).collect() } else { (vy..=vx).rev().collect() };
+        for (i, reg) in regs.into_iter().enumerate() {
+            self.vreg[reg] = self.mem.read_byte(self.ireg + i);
         }
     }
 
+    // Load IREG with the 16-bit address in the next word
+    fn op_ldi_xo(&mut self) {
+        self.ireg = ((self.mem.read_byte(self.pc) as Addr) << 8) | self.mem.read_byte(self.pc + 1) as Addr;
+        self.incr_pc();
+    }
+
+    // Select the bitplanes drawn to
+    fn op_planes(&mut self, planes: ByteVal) {
+        self.planes = planes & 0x3;
+        let mut display = self.display.borrow_mut();
+        (*display).select_planes(self.planes);
+    }
+
+    // Load the 16 byte audio pattern from [IREG]
+    fn op_ldaudio(&mut self) {
+        for i in 0..16 {
+            self.audio_pattern[i] = self.mem.read_byte(self.ireg + i);
+        }
+    }
+
+    // Set the audio pattern playback pitch
+    fn op_pitch(&mut self, vx: RegNum) {
+        self.pitch = self.vreg[vx];
+    }
+
     fn op_undef(&mut self) {
         eprintln!("Unknown instruction!");
     }
@@ -397,3 +910,89 @@ impl<'a> CPU<'a> {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8::link::{link, AudioEvent};
+    use chip8::memory::Memory;
+
+    // Run the CHIP-8 instruction at 0x200 with a machine code routine at
+    // 0x300
+    fn call_routine(routine: &[ByteVal]) -> (Addr, ByteVal, Addr) {
+        let (display, keyboard, audio) = link().0.into_interfaces();
+        let mut mem = Memory::new();
+        mem.write_byte(0x200, 0x03);
+        mem.write_byte(0x201, 0x00);
+        for (i, &byte) in routine.iter().enumerate() {
+            mem.write_byte(0x300 + i, byte);
+        }
+
+        let mut cpu = CPU::new(&mut mem, &display, &keyboard, &audio, MachineVariant::Chip8);
+        cpu.set_vip_hybrid(true);
+        cpu.ireg = 0x123;
+        let (opval, _) = cpu.fetch_op();
+        cpu.decode_and_execute_op(opval);
+        (cpu.pc(), cpu.vreg(3), cpu.ireg)
+    }
+
+    #[test]
+    fn machine_code_returns_with_sep_r4() {
+        // LDI 42, STR R6 (V3, from the call address), SEP R4
+        let (pc, v3, ireg) = call_routine(&[0xf8, 0x42, 0x56, 0xd4]);
+        assert_eq!(pc, 0x202);
+        assert_eq!(v3, 0x42);
+        assert_eq!(ireg, 0x123);
+    }
+
+    #[test]
+    fn machine_code_can_change_i() {
+        // LDI 45, PLO RA, SEP R4
+        let (pc, _, ireg) = call_routine(&[0xf8, 0x45, 0xaa, 0xd4]);
+        assert_eq!(pc, 0x202);
+        assert_eq!(ireg, 0x145);
+    }
+
+    #[test]
+    fn machine_code_that_idles_is_abandoned() {
+        // LDI 42, STR R6, IDL
+        let (pc, v3, _) = call_routine(&[0xf8, 0x42, 0x56, 0x00]);
+        assert_eq!(pc, 0x202);
+        assert_eq!(v3, 0x42);
+    }
+
+    // Memory that panics on any address past its end
+    struct TestMemory(Vec<ByteVal>);
+
+    impl MemoryInterface for TestMemory {
+        fn read_byte(&self, addr: Addr) -> ByteVal {
+            self.0[addr]
+        }
+
+        fn write_byte(&mut self, addr: Addr, val: ByteVal) {
+            self.0[addr] = val;
+        }
+    }
+
+    #[test]
+    fn megachip_reads_stop_at_the_end_of_memory() {
+        let (core, frontend) = link();
+        let (display, keyboard, audio) = core.into_interfaces();
+        let mut mem = TestMemory(vec![0xff; MachineVariant::MegaChip.memory_size()]);
+        let mut cpu = CPU::new(&mut mem, &display, &keyboard, &audio, MachineVariant::MegaChip);
+        cpu.op_megachip(true);
+
+        // A sample header claiming 16M bytes, 16 bytes from the top
+        cpu.ireg = 0xfffff0;
+        cpu.op_play_sample(false);
+        match frontend.audio.try_recv() {
+            Ok(AudioEvent::Play(sample, _)) => assert_eq!(sample.samples.len(), 10),
+            _                               => panic!("no sample played")
+        }
+
+        cpu.mega.sprite_width = 256;
+        cpu.mega.sprite_height = 256;
+        cpu.op_drw_mega(0, 0);
+        cpu.op_ldpal(255);
+    }
+}
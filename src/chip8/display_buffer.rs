@@ -1,61 +1,611 @@
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
 
 use chip8::core::DisplayInterface;
 use chip8::types::ByteVal;
+use chip8::megachip::MegaFrameBuffer;
+use chip8::screenshot;
 
-pub const WIDTH: usize = 64;
-pub const HEIGHT: usize = 32;
+pub const DEFAULT_WIDTH: usize = 64;
+pub const DEFAULT_HEIGHT: usize = 32;
+
+// CHIP-8X foreground colours apply to zones 8 pixels wide and one pixel high
+pub const COLOUR_ZONE_WIDTH: usize = 8;
+
+// The VP-590 cycles through blue, black, green and red backgrounds
+pub const BACKGROUND_COLOURS: ByteVal = 4;
+
+// Foreground colour at power-on, red
+const DEFAULT_ZONE_COLOUR: ByteVal = 1;
+
+struct ColourAttributes {
+    background: ByteVal,
+    zones:      Vec<ByteVal>
+}
+
+impl ColourAttributes {
+    fn new(width: usize, height: usize) -> Self {
+        ColourAttributes {
+            background: 0,
+            zones:      vec![DEFAULT_ZONE_COLOUR; (width / COLOUR_ZONE_WIDTH) * height]
+        }
+    }
+}
+
+// XO-CHIP draws to two bitplanes. Each pixel holds a bit per plane, and
+// drawing, clearing and scrolling only affect the selected planes.
+pub const DEFAULT_PLANES: ByteVal = 0x1;
+pub const PLANES: usize = 2;
+
+// Each row is packed as a bit per pixel in each plane, with x = 0 in the
+// most significant bit, so sprites are drawn and tested for collisions a
+// whole row at a time
+pub const MAX_WIDTH: usize = 128;
+pub type PackedRow = [u128; PLANES];
+
+fn column_bit(x: usize) -> u128 {
+    1 << (MAX_WIDTH - 1 - x)
+}
+
+// The leftmost `width` columns
+fn columns_mask(width: usize) -> u128 {
+    if width == 0 { 0 } else { !0 << (MAX_WIDTH - width) }
+}
+
+fn packed_pixel(row: &PackedRow, x: usize) -> ByteVal {
+    let bit = column_bit(x);
+    (0..PLANES).fold(0, |pixel, plane| if row[plane] & bit != 0 { pixel | 1 << plane } else { pixel })
+}
+
+// The columns changed in each row, as half-open spans, so renderers only
+// repaint what changed
+#[derive(Clone, Debug)]
+pub struct DirtyRegion {
+    width: usize,
+    spans: Vec<Option<(usize, usize)>>
+}
+
+impl DirtyRegion {
+    pub fn new(width: usize, height: usize) -> Self {
+        DirtyRegion {
+            width: width,
+            spans: vec![None; height]
+        }
+    }
+
+    pub fn all(width: usize, height: usize) -> Self {
+        let mut region = DirtyRegion::new(width, height);
+        region.mark_all();
+        region
+    }
+
+    // Areas outside the display are clipped
+    pub fn mark(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        let (left, right) = (x.min(self.width), (x + width).min(self.width));
+        if left == right {
+            return;
+        }
+        let bottom = (y + height).min(self.spans.len());
+        for span in self.spans[y.min(bottom)..bottom].iter_mut() {
+            *span = Some(match *span {
+                Some((l, r)) => (l.min(left), r.max(right)),
+                None         => (left, right)
+            });
+        }
+    }
+
+    pub fn mark_all(&mut self) {
+        let (width, height) = (self.width, self.spans.len());
+        self.mark(0, 0, width, height);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spans.iter().all(|span| span.is_none())
+    }
+
+    // Columns changed in any of the given rows
+    pub fn span(&self, y: usize, height: usize) -> Option<(usize, usize)> {
+        self.spans.iter().skip(y).take(height).fold(None, |span, &row| match (span, row) {
+            (Some((l, r)), Some((left, right))) => Some((l.min(left), r.max(right))),
+            (span, row)                         => span.or(row)
+        })
+    }
+
+    // Rectangles (x, y, width, height) covering the changes, one for each
+    // run of changed rows
+    pub fn rects(&self) -> Vec<(usize, usize, usize, usize)> {
+        let mut rects = Vec::new();
+        let mut band: Option<(usize, usize, usize)> = None;
+        for (y, span) in self.spans.iter().enumerate() {
+            band = match (band, *span) {
+                (Some((top, l, r)), Some((left, right))) => Some((top, l.min(left), r.max(right))),
+                (None, Some((left, right)))              => Some((y, left, right)),
+                (Some((top, l, r)), None)                => {
+                    rects.push((l, top, r - l, y - top));
+                    None
+                },
+                (None, None)                             => None
+            };
+        }
+        if let Some((top, l, r)) = band {
+            rects.push((l, top, r - l, self.spans.len() - top));
+        }
+        rects
+    }
+}
+
+// A complete frame, as published to the frontend at each vblank
+#[derive(Clone)]
+pub struct Frame {
+    pub width:      usize,
+    pub height:     usize,
+    pub rows:       Vec<PackedRow>,
+    // CHIP-8X colour attributes, as VP-590 palette indices
+    pub background: Option<ByteVal>,
+    pub zones:      Vec<ByteVal>,
+    // MegaChip frames are ARGB, and take the place of the pixels
+    pub argb:       Option<Vec<u32>>,
+    // What changed since the frame before, and the count of frames
+    // published. A frontend that skipped a frame has to repaint it all.
+    pub dirty:      DirtyRegion,
+    pub sequence:   u64
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Frame {
+            width:      DEFAULT_WIDTH,
+            height:     DEFAULT_HEIGHT,
+            rows:       vec![[0; PLANES]; DEFAULT_HEIGHT],
+            background: None,
+            zones:      Vec::new(),
+            argb:       None,
+            dirty:      DirtyRegion::all(DEFAULT_WIDTH, DEFAULT_HEIGHT),
+            sequence:   0
+        }
+    }
+
+    // Whether the dirty region is relative to the given frame
+    pub fn follows(&self, sequence: u64) -> bool {
+        self.sequence == sequence + 1
+    }
+
+    pub fn dimensions(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    // The bit planes of a pixel
+    pub fn pixel(&self, x: usize, y: usize) -> ByteVal {
+        if x < self.width && y < self.height && self.argb.is_none() {
+            packed_pixel(&self.rows[y], x)
+        } else {
+            0
+        }
+    }
+
+    // Foreground colour of a pixel, when colour is enabled
+    pub fn pixel_colour(&self, x: usize, y: usize) -> Option<ByteVal> {
+        let zones_per_row = self.width / COLOUR_ZONE_WIDTH;
+        if self.background.is_some() && x < self.width && y < self.height {
+            self.zones.get(y * zones_per_row + x / COLOUR_ZONE_WIDTH).cloned()
+        } else {
+            None
+        }
+    }
+
+    // The frame as ARGB, with colours indexed by each pixel's bit planes.
+    // Missing colours default to black and white.
+    pub fn to_argb(&self, colours: &[u32]) -> Vec<u32> {
+        if let Some(ref argb) = self.argb {
+            return argb.iter().map(|&pixel| 0xff00_0000 | pixel).collect();
+        }
+        let mut argb = Vec::with_capacity(self.width * self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let pixel = self.pixel(x, y);
+                let colour = colours.get(pixel as usize).cloned().unwrap_or(if pixel == 0 { 0 } else { 0xffffff });
+                argb.push(0xff00_0000 | colour);
+            }
+        }
+        argb
+    }
+}
 
 pub struct DisplayBuffer {
-    mem: [ByteVal; WIDTH * HEIGHT]
+    width:  usize,
+    height: usize,
+    rows:   Vec<PackedRow>,
+    planes: ByteVal,
+    colour: Option<ColourAttributes>,
+    // Changed since the last vblank, and in the frame it published
+    dirty:  DirtyRegion,
+    shown:  DirtyRegion
 }
 
 impl DisplayBuffer {
     pub fn new() -> Self {
         DisplayBuffer {
-            mem: [0; WIDTH * HEIGHT]
+            width:  DEFAULT_WIDTH,
+            height: DEFAULT_HEIGHT,
+            rows:   vec![[0; PLANES]; DEFAULT_HEIGHT],
+            planes: DEFAULT_PLANES,
+            colour: None,
+            dirty:  DirtyRegion::new(DEFAULT_WIDTH, DEFAULT_HEIGHT),
+            shown:  DirtyRegion::all(DEFAULT_WIDTH, DEFAULT_HEIGHT)
+        }
+    }
+
+    // Copy the display into a frame for the frontend, reusing its storage
+    pub fn copy_frame(&self, frame: &mut Frame) {
+        frame.width = self.width;
+        frame.height = self.height;
+        frame.rows.clone_from(&self.rows);
+        frame.argb = None;
+        frame.dirty.clone_from(&self.shown);
+        match self.colour {
+            Some(ref colour) => {
+                frame.background = Some(colour.background);
+                frame.zones.clone_from(&colour.zones);
+            },
+            None             => {
+                frame.background = None;
+                frame.zones.clear();
+            }
+        }
+    }
+
+    pub fn frame(&self) -> Frame {
+        let mut frame = Frame::new();
+        self.copy_frame(&mut frame);
+        frame
+    }
+
+    // Save the display without a frontend, e.g. to dump frames from tests
+    pub fn write_png<P: AsRef<Path>>(&self, path: P, scale: usize, colours: &[u32]) -> io::Result<()> {
+        screenshot::write_png(path, &self.frame().to_argb(colours), self.dimensions(), scale)
+    }
+
+    // Pixels outside the display are clipped
+    fn contains(&self, x: usize, y: usize) -> bool {
+        x < self.width && y < self.height
+    }
+
+    fn plane_selected(&self, plane: usize) -> bool {
+        self.planes & (1 << plane) != 0
+    }
+
+    fn mark_columns(&mut self, y: usize, columns: u128) {
+        if columns != 0 {
+            let left = columns.leading_zeros() as usize;
+            let right = MAX_WIDTH - columns.trailing_zeros() as usize;
+            self.dirty.mark(left, y, right - left, 1);
+        }
+    }
+
+    // Set the masked pixels of a row to `bits` in the selected planes
+    fn set_row(&mut self, y: usize, mask: u128, bits: u128) {
+        let mut changed = 0;
+        for plane in 0..PLANES {
+            if self.plane_selected(plane) {
+                let old = self.rows[y][plane];
+                self.rows[y][plane] = (old & !mask) | (bits & mask);
+                changed |= old ^ self.rows[y][plane];
+            }
+        }
+        self.mark_columns(y, changed);
+    }
+
+    // XOR `bits` into a row in the selected planes, returning true if a lit
+    // pixel was cleared
+    fn xor_row(&mut self, y: usize, bits: u128) -> bool {
+        let mut cleared = false;
+        let mut changed = 0;
+        for plane in 0..PLANES {
+            if self.plane_selected(plane) {
+                cleared = cleared || self.rows[y][plane] & bits != 0;
+                self.rows[y][plane] ^= bits;
+                changed |= bits;
+            }
         }
+        self.mark_columns(y, changed);
+        cleared
     }
 }
 
 impl DisplayInterface for DisplayBuffer {
     fn dimensions(&self) -> (usize, usize) {
-        (WIDTH, HEIGHT)
+        (self.width, self.height)
+    }
+
+    // Rows hold at most MAX_WIDTH pixels
+    fn set_dimensions(&mut self, width: usize, height: usize) {
+        let width = width.min(MAX_WIDTH);
+        self.width = width;
+        self.height = height;
+        self.dirty = DirtyRegion::all(width, height);
+        self.rows = vec![[0; PLANES]; height];
+        if self.colour.is_some() {
+            self.colour = Some(ColourAttributes::new(width, height));
+        }
+    }
+
+    fn set_colour_enabled(&mut self, enabled: bool) {
+        self.dirty.mark_all();
+        self.colour = if enabled { Some(ColourAttributes::new(self.width, self.height)) } else { None };
+    }
+
+    fn cycle_background_colour(&mut self) {
+        self.dirty.mark_all();
+        if let Some(ref mut colour) = self.colour {
+            colour.background = (colour.background + 1) % BACKGROUND_COLOURS;
+        }
+    }
+
+    fn set_zone_colour(&mut self, x: usize, y: usize, width: usize, height: usize, val: ByteVal) {
+        let zones_per_row = self.width / COLOUR_ZONE_WIDTH;
+        let display_height = self.height;
+        if let Some(ref mut colour) = self.colour {
+            let first_zone = x / COLOUR_ZONE_WIDTH;
+            let last_zone = (x + width + COLOUR_ZONE_WIDTH - 1) / COLOUR_ZONE_WIDTH;
+            for zy in y..(y + height).min(display_height) {
+                for zx in first_zone..last_zone.min(zones_per_row) {
+                    colour.zones[zy * zones_per_row + zx] = val;
+                }
+            }
+            self.dirty.mark(first_zone * COLOUR_ZONE_WIDTH, y, (last_zone - first_zone) * COLOUR_ZONE_WIDTH, height);
+        }
+    }
+
+    fn background_colour(&self) -> Option<ByteVal> {
+        self.colour.as_ref().map(|colour| colour.background)
+    }
+
+    fn pixel_colour(&self, x: ByteVal, y: ByteVal) -> Option<ByteVal> {
+        let zones_per_row = self.width / COLOUR_ZONE_WIDTH;
+        match self.colour {
+            Some(ref colour) if self.contains(x as usize, y as usize) => {
+                Some(colour.zones[y as usize * zones_per_row + x as usize / COLOUR_ZONE_WIDTH])
+            },
+            _                                                          => None
+        }
     }
     
     fn clear(&mut self) {
-        self.mem = [0; WIDTH * HEIGHT];
+        for y in 0..self.height {
+            self.set_row(y, !0, 0);
+        }
+    }
+
+    fn select_planes(&mut self, planes: ByteVal) {
+        self.planes = planes;
+    }
+
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        self.dirty.mark_all();
+        let old = self.rows.clone();
+        let columns = columns_mask(self.width);
+        for y in 0..self.height {
+            let sy = y as isize - dy;
+            let source = if sy >= 0 && (sy as usize) < self.height { old[sy as usize] } else { [0; PLANES] };
+            for plane in 0..PLANES {
+                if self.plane_selected(plane) {
+                    // Shifting right moves pixels right
+                    let moved = if dx >= 0 {
+                        source[plane].checked_shr(dx as u32)
+                    } else {
+                        source[plane].checked_shl((-dx) as u32)
+                    };
+                    self.rows[y][plane] = moved.unwrap_or(0) & columns;
+                }
+            }
+        }
     }
 
     fn read_pixel(&self, x: ByteVal, y: ByteVal) -> ByteVal {
-        let off = y as usize * WIDTH + x as usize;
-        return self.mem[off];
+        if self.contains(x as usize, y as usize) {
+            packed_pixel(&self.rows[y as usize], x as usize)
+        } else {
+            0
+        }
     }
     
     fn write_pixel(&mut self, x: ByteVal, y: ByteVal, val: ByteVal) {
-        let off = y as usize * WIDTH + x as usize;
-        self.mem[off] = if val == 0 { 0 } else { 1 };
+        if self.contains(x as usize, y as usize) {
+            self.set_row(y as usize, column_bit(x as usize), if val == 0 { 0 } else { !0 });
+        }
     }
 
     fn write_pixel_xor(&mut self, x: ByteVal, y: ByteVal, val: ByteVal) -> bool {
-        let off = y as usize * WIDTH + x as usize;
-        let val = if val == 0 { 0 } else { 1 };
-        let cleared = self.mem[off] == 1 && val == 1;
-        self.mem[off] ^= val;
-        cleared
+        if val != 0 && self.contains(x as usize, y as usize) {
+            self.xor_row(y as usize, column_bit(x as usize))
+        } else {
+            false
+        }
     }
 
+    // The VIP rewrites the whole display every frame, so only pixels that
+    // change are marked dirty
     fn write_pixel_row(&mut self, x: ByteVal, y : ByteVal, rowval: ByteVal) {
-        for i in 0..8 {
-            self.write_pixel(x + i, y, rowval >> (7 - i));
+        if self.contains(x as usize, y as usize) {
+            let mask = (0xff << (MAX_WIDTH - 8)) >> x;
+            let bits = ((rowval as u128) << (MAX_WIDTH - 8)) >> x;
+            self.set_row(y as usize, mask & columns_mask(self.width), bits);
         }
     }
     
     fn write_pixel_row_xor(&mut self, x: ByteVal, y : ByteVal, rowval: ByteVal) -> bool {
-        let mut cleared = false;
-        for i in 0..8 {
-            let pixel_cleared = self.write_pixel_xor(x + i, y, (rowval >> (7 - i)) & 1);
-            cleared = cleared || pixel_cleared;
+        self.xor_sprite_row(x as usize, y as usize, (rowval as u16) << 8, false)
+    }
+
+    fn xor_sprite_row(&mut self, x: usize, y: usize, bits: u16, wrap: bool) -> bool {
+        if !self.contains(x, y) {
+            return false;
         }
-        cleared
+        let sprite = (bits as u128) << (MAX_WIDTH - 16);
+        let mut row = sprite >> x;
+        // Pixels past the right edge come back in on the left
+        if wrap && x + 16 > self.width {
+            row |= sprite << (self.width - x);
+        }
+        self.xor_row(y, row & columns_mask(self.width))
+    }
+
+    fn set_megachip_enabled(&mut self, _enabled: bool) {
+    }
+
+    fn megachip(&mut self) -> Option<&mut MegaFrameBuffer> {
+        None
+    }
+
+    // The frame is only worth publishing if something has been drawn
+    fn vblank(&mut self) -> bool {
+        if self.dirty.is_empty() {
+            return false;
+        }
+        mem::swap(&mut self.dirty, &mut self.shown);
+        self.dirty = DirtyRegion::new(self.width, self.height);
+        true
+    }
+}
+
+// Lets frontends sleep until the core publishes a frame
+pub struct VBlankSignal {
+    frame: Mutex<u64>,
+    cond:  Condvar
+}
+
+impl VBlankSignal {
+    pub fn new() -> Self {
+        VBlankSignal {
+            frame: Mutex::new(0),
+            cond:  Condvar::new()
+        }
+    }
+
+    pub fn notify(&self) {
+        *self.frame.lock().unwrap() += 1;
+        self.cond.notify_all();
+    }
+
+    // Wait for a vblank after `seen`, or until the timeout passes, returning
+    // the latest vblank count
+    pub fn wait(&self, seen: u64, timeout: Duration) -> u64 {
+        let frame = self.frame.lock().unwrap();
+        if *frame != seen {
+            return *frame;
+        }
+        let (frame, _) = self.cond.wait_timeout(frame, timeout).unwrap();
+        *frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display(width: usize, height: usize) -> DisplayBuffer {
+        let mut display = DisplayBuffer::new();
+        display.set_dimensions(width, height);
+        display
+    }
+
+    fn lit_columns(display: &DisplayBuffer, y: usize) -> Vec<usize> {
+        (0..display.width).filter(|&x| display.read_pixel(x as ByteVal, y as ByteVal) != 0).collect()
+    }
+
+    // Columns a sprite row should light, drawing one pixel at a time
+    fn expected_columns(width: usize, x: usize, bits: u16, wrap: bool) -> Vec<usize> {
+        let mut columns: Vec<usize> = (0..16)
+            .filter(|bit| bits & (0x8000 >> bit) != 0)
+            .map(|bit| x + bit)
+            .filter(|&px| wrap || px < width)
+            .map(|px| px % width)
+            .collect();
+        columns.sort();
+        columns
+    }
+
+    #[test]
+    fn sprites_clip_or_wrap_at_the_right_edge() {
+        for &width in [64, 128].iter() {
+            for &bits in [0xff00u16, 0xa500, 0xffff, 0xc3a5].iter() {
+                for &x in [0, width - 8, width - 1].iter() {
+                    for &wrap in [false, true].iter() {
+                        let mut buffer = display(width, 32);
+                        assert!(!buffer.xor_sprite_row(x, 5, bits, wrap));
+                        assert_eq!(lit_columns(&buffer, 5), expected_columns(width, x, bits, wrap),
+                                   "width {} bits {:04x} x {} wrap {}", width, bits, x, wrap);
+                        assert!(lit_columns(&buffer, 4).is_empty());
+                        assert!(lit_columns(&buffer, 6).is_empty());
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn drawing_over_lit_pixels_collides() {
+        let mut buffer = display(64, 32);
+        assert!(!buffer.xor_sprite_row(10, 3, 0xf000, false));
+        // Touching but not overlapping
+        assert!(!buffer.xor_sprite_row(14, 3, 0xf000, false));
+        assert!(buffer.xor_sprite_row(12, 3, 0x8000, false));
+        assert_eq!(lit_columns(&buffer, 3), vec![10, 11, 13, 14, 15, 16, 17]);
+
+        // Drawing the same sprite again erases it
+        let mut buffer = display(64, 32);
+        buffer.xor_sprite_row(60, 0, 0xffff, true);
+        assert!(buffer.xor_sprite_row(60, 0, 0xffff, true));
+        assert!(lit_columns(&buffer, 0).is_empty());
+    }
+
+    #[test]
+    fn wrapped_pixels_collide() {
+        let mut buffer = display(64, 32);
+        buffer.write_pixel(1, 7, 1);
+        assert!(!buffer.xor_sprite_row(60, 7, 0xff00, false));
+        assert!(buffer.xor_sprite_row(60, 7, 0xff00, true));
+    }
+
+    #[test]
+    fn byte_rows_match_sprite_rows() {
+        let mut buffer = display(64, 32);
+        assert!(!buffer.write_pixel_row_xor(58, 2, 0b1011_0001));
+        assert_eq!(lit_columns(&buffer, 2), vec![58, 60, 61]);
+        assert!(buffer.write_pixel_row_xor(60, 2, 0b1000_0000));
+        assert_eq!(lit_columns(&buffer, 2), vec![58, 61]);
+    }
+
+    #[test]
+    fn width_is_clamped_to_a_row() {
+        let mut buffer = display(256, 64);
+        assert_eq!(buffer.dimensions(), (MAX_WIDTH, 64));
+        buffer.xor_sprite_row(MAX_WIDTH - 8, 0, 0xffff, false);
+        assert_eq!(lit_columns(&buffer, 0), (MAX_WIDTH - 8..MAX_WIDTH).collect::<Vec<_>>());
+        assert_eq!(buffer.read_pixel(255, 0), 0);
+    }
+
+    #[test]
+    fn planes_are_drawn_separately() {
+        let mut buffer = display(128, 64);
+        buffer.select_planes(2);
+        buffer.xor_sprite_row(0, 0, 0x8000, false);
+        buffer.select_planes(3);
+        assert!(buffer.xor_sprite_row(0, 0, 0xc000, false));
+        assert_eq!(buffer.read_pixel(0, 0), 1);
+        assert_eq!(buffer.read_pixel(1, 0), 3);
+    }
+
+    #[test]
+    fn vblank_reports_changes() {
+        let mut buffer = display(64, 32);
+        assert!(buffer.vblank());
+        assert!(!buffer.vblank());
+        buffer.xor_sprite_row(8, 4, 0xf000, false);
+        assert!(buffer.vblank());
+        assert_eq!(buffer.shown.span(4, 1), Some((8, 12)));
     }
 }
@@ -0,0 +1,407 @@
+use std::cell::{RefCell, UnsafeCell};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use chip8::types::ByteVal;
+use chip8::core::{KeyboardInterface, DisplayInterface, AudioInterface};
+use chip8::core::{RcRefKeyboardInterface, RcRefDisplayInterface, RcRefAudioInterface};
+use chip8::display_buffer::{DisplayBuffer, Frame, VBlankSignal};
+use chip8::megachip::{MegaFrameBuffer, MegaSample};
+
+// The core runs on a thread of its own and owns its display and keypad
+// state. It shares nothing with the frontend that needs a lock: frames pass
+// through a triple buffer, keys through atomic bitmasks and audio through a
+// channel.
+
+// Set in the middle slot index when it holds a frame the reader hasn't seen
+const FRESH: usize = 4;
+
+struct TripleBuffer<T> {
+    slots:  [UnsafeCell<T>; 3],
+    middle: AtomicUsize
+}
+
+// Each slot is only touched by the side whose index refers to it, and the
+// indices are handed over by atomic swaps
+unsafe impl<T: Send> Sync for TripleBuffer<T> {}
+
+// The writer fills the back slot and swaps it with the middle one, without
+// ever waiting for the reader
+pub struct FramePublisher<T> {
+    buffer: Arc<TripleBuffer<T>>,
+    back:   usize
+}
+
+impl<T> FramePublisher<T> {
+    pub fn back_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.buffer.slots[self.back].get() }
+    }
+
+    pub fn publish(&mut self) {
+        self.back = self.buffer.middle.swap(self.back | FRESH, Ordering::AcqRel) & !FRESH;
+    }
+}
+
+// The reader keeps showing its front slot until a newer one is published
+pub struct FrameReceiver<T> {
+    buffer: Arc<TripleBuffer<T>>,
+    front:  usize
+}
+
+impl<T> FrameReceiver<T> {
+    // Take the latest frame, returning false if there isn't a new one
+    pub fn update(&mut self) -> bool {
+        if self.buffer.middle.load(Ordering::Acquire) & FRESH == 0 {
+            return false;
+        }
+        self.front = self.buffer.middle.swap(self.front, Ordering::AcqRel) & !FRESH;
+        true
+    }
+
+    pub fn front(&self) -> &T {
+        unsafe { &*self.buffer.slots[self.front].get() }
+    }
+}
+
+pub fn triple_buffer<T: Clone>(initial: T) -> (FramePublisher<T>, FrameReceiver<T>) {
+    let buffer = Arc::new(TripleBuffer {
+        slots:  [UnsafeCell::new(initial.clone()), UnsafeCell::new(initial.clone()), UnsafeCell::new(initial)],
+        middle: AtomicUsize::new(1)
+    });
+    (FramePublisher { buffer: buffer.clone(), back: 0 }, FrameReceiver { buffer: buffer, front: 2 })
+}
+
+// Bits 0-15 of the pressed mask are the main keypad and bits 16-31 the
+// CHIP-8X second keypad. Bits 0-15 of the polled mask are the keys tested
+// since the frontend last looked, and bit 16 a wait for any key.
+const SECOND_KEYPAD_SHIFT: u32 = 16;
+const WAIT_BIT: u32 = 1 << 16;
+
+pub struct KeyState {
+    pressed: AtomicU32,
+    polled:  AtomicU32
+}
+
+impl KeyState {
+    pub fn new() -> Self {
+        KeyState {
+            pressed: AtomicU32::new(0),
+            polled:  AtomicU32::new(0)
+        }
+    }
+
+    fn set_bit(&self, bit: u32, pressed: bool) {
+        if pressed {
+            self.pressed.fetch_or(1 << bit, Ordering::Release);
+        } else {
+            self.pressed.fetch_and(!(1 << bit), Ordering::Release);
+        }
+    }
+
+    pub fn set_key(&self, key: ByteVal, pressed: bool) {
+        self.set_bit((key & 0xf) as u32, pressed);
+    }
+
+    pub fn set_second_key(&self, key: ByteVal, pressed: bool) {
+        self.set_bit((key & 0xf) as u32 + SECOND_KEYPAD_SHIFT, pressed);
+    }
+
+    pub fn is_pressed(&self, key: ByteVal) -> bool {
+        self.pressed.load(Ordering::Acquire) & (1 << (key & 0xf)) != 0
+    }
+
+    pub fn is_second_pressed(&self, key: ByteVal) -> bool {
+        self.pressed.load(Ordering::Acquire) & (1 << ((key & 0xf) as u32 + SECOND_KEYPAD_SHIFT)) != 0
+    }
+
+    pub fn pressed_keys(&self) -> [bool; 16] {
+        let pressed = self.pressed.load(Ordering::Acquire);
+        let mut keys = [false; 16];
+        for key in 0..16 {
+            keys[key] = pressed & (1 << key) != 0;
+        }
+        keys
+    }
+
+    // None records a wait for any key
+    pub fn record_poll(&self, key: Option<ByteVal>) {
+        let bit = match key {
+            Some(key) => 1 << (key & 0xf),
+            None      => WAIT_BIT
+        };
+        self.polled.fetch_or(bit, Ordering::Release);
+    }
+
+    // The polls recorded since the last call, in the form `record_poll` took
+    pub fn take_polls(&self) -> Vec<Option<ByteVal>> {
+        let polled = self.polled.swap(0, Ordering::AcqRel);
+        let mut polls: Vec<Option<ByteVal>> = (0..16)
+            .filter(|key| polled & (1 << key) != 0)
+            .map(|key| Some(key as ByteVal))
+            .collect();
+        if polled & WAIT_BIT != 0 {
+            polls.push(None);
+        }
+        polls
+    }
+}
+
+pub enum AudioEvent {
+    Play(MegaSample, bool),
+    Stop
+}
+
+// The display the core draws to. The MegaChip framebuffer replaces the
+// normal one while enabled.
+pub struct CoreDisplay {
+    display_buffer: DisplayBuffer,
+    megachip:       Option<MegaFrameBuffer>,
+    frames:         FramePublisher<Frame>,
+    published:      u64,
+    vblank_signal:  Arc<VBlankSignal>
+}
+
+impl CoreDisplay {
+    fn active_display(&self) -> &dyn DisplayInterface {
+        match self.megachip {
+            Some(ref mega) => mega,
+            None           => &self.display_buffer
+        }
+    }
+
+    fn active_display_mut(&mut self) -> &mut dyn DisplayInterface {
+        match self.megachip {
+            Some(ref mut mega) => mega,
+            None               => &mut self.display_buffer
+        }
+    }
+}
+
+impl DisplayInterface for CoreDisplay {
+    fn dimensions(&self) -> (usize, usize) {
+        self.active_display().dimensions()
+    }
+
+    fn set_dimensions(&mut self, width: usize, height: usize) {
+        self.display_buffer.set_dimensions(width, height);
+    }
+
+    fn clear(&mut self) {
+        self.active_display_mut().clear();
+    }
+
+    fn select_planes(&mut self, planes: ByteVal) {
+        self.active_display_mut().select_planes(planes);
+    }
+
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        self.active_display_mut().scroll(dx, dy);
+    }
+
+    fn read_pixel(&self, x: ByteVal, y: ByteVal) -> ByteVal {
+        self.active_display().read_pixel(x, y)
+    }
+
+    fn write_pixel(&mut self, x: ByteVal, y: ByteVal, val: ByteVal) {
+        self.active_display_mut().write_pixel(x, y, val);
+    }
+
+    fn write_pixel_xor(&mut self, x: ByteVal, y: ByteVal, val: ByteVal) -> bool {
+        self.active_display_mut().write_pixel_xor(x, y, val)
+    }
+
+    fn write_pixel_row(&mut self, x: ByteVal, y : ByteVal, rowval: ByteVal) {
+        self.active_display_mut().write_pixel_row(x, y, rowval);
+    }
+
+    fn write_pixel_row_xor(&mut self, x: ByteVal, y : ByteVal, rowval: ByteVal) -> bool {
+        self.active_display_mut().write_pixel_row_xor(x, y, rowval)
+    }
+
+    fn xor_sprite_row(&mut self, x: usize, y: usize, bits: u16, wrap: bool) -> bool {
+        self.active_display_mut().xor_sprite_row(x, y, bits, wrap)
+    }
+
+    fn set_colour_enabled(&mut self, enabled: bool) {
+        self.display_buffer.set_colour_enabled(enabled);
+    }
+
+    fn cycle_background_colour(&mut self) {
+        self.display_buffer.cycle_background_colour();
+    }
+
+    fn set_zone_colour(&mut self, x: usize, y: usize, width: usize, height: usize, val: ByteVal) {
+        self.display_buffer.set_zone_colour(x, y, width, height, val);
+    }
+
+    fn background_colour(&self) -> Option<ByteVal> {
+        self.display_buffer.background_colour()
+    }
+
+    fn pixel_colour(&self, x: ByteVal, y: ByteVal) -> Option<ByteVal> {
+        self.display_buffer.pixel_colour(x, y)
+    }
+
+    fn set_megachip_enabled(&mut self, enabled: bool) {
+        self.megachip = if enabled { Some(MegaFrameBuffer::new()) } else { None };
+    }
+
+    fn megachip(&mut self) -> Option<&mut MegaFrameBuffer> {
+        self.megachip.as_mut()
+    }
+
+    // Hand the frame to the frontend, and wake it at every vblank so it can
+    // keep time with the core
+    fn vblank(&mut self) -> bool {
+        let published = self.active_display_mut().vblank();
+        if published {
+            self.published += 1;
+            let frame = self.frames.back_mut();
+            match self.megachip {
+                Some(ref mega) => mega.copy_frame(frame),
+                None           => self.display_buffer.copy_frame(frame)
+            }
+            frame.sequence = self.published;
+            self.frames.publish();
+        }
+        self.vblank_signal.notify();
+        published
+    }
+}
+
+pub struct CoreKeyboard {
+    keys: Arc<KeyState>
+}
+
+impl KeyboardInterface for CoreKeyboard {
+    fn key_pressed(&self, key: ByteVal) -> bool {
+        self.keys.is_pressed(key)
+    }
+
+    fn second_key_pressed(&self, key: ByteVal) -> bool {
+        self.keys.is_second_pressed(key)
+    }
+
+    fn key_polled(&mut self, key: Option<ByteVal>) {
+        self.keys.record_poll(key);
+    }
+}
+
+pub struct CoreAudio {
+    events: Sender<AudioEvent>
+}
+
+impl AudioInterface for CoreAudio {
+    // A frontend that has gone away no longer plays anything
+    fn play_sample(&mut self, sample: MegaSample, looped: bool) {
+        let _ = self.events.send(AudioEvent::Play(sample, looped));
+    }
+
+    fn stop_sample(&mut self) {
+        let _ = self.events.send(AudioEvent::Stop);
+    }
+}
+
+// The core's end of the link, moved to the simulation thread
+pub struct CoreIO {
+    pub display:  CoreDisplay,
+    pub keyboard: CoreKeyboard,
+    pub audio:    CoreAudio
+}
+
+impl CoreIO {
+    pub fn into_interfaces(self) -> (RcRefDisplayInterface, RcRefKeyboardInterface, RcRefAudioInterface) {
+        (Rc::new(RefCell::new(self.display)),
+         Rc::new(RefCell::new(self.keyboard)),
+         Rc::new(RefCell::new(self.audio)))
+    }
+}
+
+// The frontend's end of the link
+pub struct FrontendIO {
+    pub frames: FrameReceiver<Frame>,
+    pub keys:   Arc<KeyState>,
+    pub audio:  Receiver<AudioEvent>,
+    pub vblank: Arc<VBlankSignal>
+}
+
+pub fn link() -> (CoreIO, FrontendIO) {
+    let (publisher, receiver) = triple_buffer(Frame::new());
+    let keys = Arc::new(KeyState::new());
+    let (sender, events) = channel();
+    let vblank = Arc::new(VBlankSignal::new());
+
+    let core = CoreIO {
+        display:  CoreDisplay {
+            display_buffer: DisplayBuffer::new(),
+            megachip:       None,
+            frames:         publisher,
+            published:      0,
+            vblank_signal:  vblank.clone()
+        },
+        keyboard: CoreKeyboard { keys: keys.clone() },
+        audio:    CoreAudio { events: sender }
+    };
+    let frontend = FrontendIO {
+        frames: receiver,
+        keys:   keys,
+        audio:  events,
+        vblank: vblank
+    };
+    (core, frontend)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    const FRAMES: u64 = 100_000;
+
+    #[test]
+    fn frames_are_neither_torn_nor_repeated() {
+        let (mut publisher, mut receiver) = triple_buffer(vec![0u64; 64]);
+        let writer = thread::spawn(move || {
+            for sequence in 1..FRAMES + 1 {
+                for value in publisher.back_mut().iter_mut() {
+                    *value = sequence;
+                }
+                publisher.publish();
+            }
+        });
+
+        let mut last = 0;
+        let mut received = 0;
+        while last < FRAMES {
+            if !receiver.update() {
+                thread::yield_now();
+                continue;
+            }
+            let frame = receiver.front();
+            assert!(frame.iter().all(|&value| value == frame[0]), "torn frame");
+            assert!(frame[0] > last, "frame {} after {}", frame[0], last);
+            last = frame[0];
+            received += 1;
+        }
+        writer.join().unwrap();
+
+        assert!(received > 0);
+        assert!(!receiver.update());
+    }
+
+    #[test]
+    fn only_the_latest_frame_is_received() {
+        let (mut publisher, mut receiver) = triple_buffer(0);
+        assert!(!receiver.update());
+        for sequence in 1..4 {
+            *publisher.back_mut() = sequence;
+            publisher.publish();
+        }
+        assert!(receiver.update());
+        assert_eq!(*receiver.front(), 3);
+        assert!(!receiver.update());
+        assert_eq!(*receiver.front(), 3);
+    }
+}
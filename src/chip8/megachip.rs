@@ -0,0 +1,282 @@
+use chip8::core::DisplayInterface;
+use chip8::types::ByteVal;
+use chip8::display_buffer::{DirtyRegion, Frame};
+
+pub const MEGA_WIDTH: usize = 256;
+pub const MEGA_HEIGHT: usize = 192;
+
+// Palette index drawn by ordinary 1-bit sprites (e.g. the font)
+const MONO_INDEX: ByteVal = 0xff;
+const MONO_COLOUR: u32 = 0xffff_ffff;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlendMode {
+    Normal,
+    Alpha25,
+    Alpha50,
+    Add,
+    Multiply
+}
+
+impl BlendMode {
+    pub fn from_value(val: ByteVal) -> BlendMode {
+        match val {
+            1 => BlendMode::Alpha25,
+            2 => BlendMode::Alpha50,
+            3 => BlendMode::Add,
+            4 => BlendMode::Multiply,
+            _ => BlendMode::Normal
+        }
+    }
+}
+
+// Registers set by the MegaChip 03NN-09NN instructions
+pub struct MegaChipRegs {
+    pub sprite_width:    usize,
+    pub sprite_height:   usize,
+    pub alpha:           ByteVal,
+    pub blend:           BlendMode,
+    pub collision_index: ByteVal
+}
+
+impl MegaChipRegs {
+    pub fn new() -> Self {
+        MegaChipRegs {
+            sprite_width:    0,
+            sprite_height:   0,
+            alpha:           0xff,
+            blend:           BlendMode::Normal,
+            collision_index: 0
+        }
+    }
+}
+
+// Digitised sound stored at I by 060N: a 16-bit sample rate, a 24-bit
+// length and then unsigned 8-bit samples
+pub struct MegaSample {
+    pub rate:    u32,
+    pub samples: Vec<ByteVal>
+}
+
+// 256x192 ARGB display. Sprites are drawn to the back buffer, which 00E0
+// presents and then clears.
+pub struct MegaFrameBuffer {
+    palette:   Vec<u32>,
+    indices:   Vec<ByteVal>,
+    back:      Vec<u32>,
+    front:     Vec<u32>,
+    // Whether the front buffer changed since the last vblank
+    presented: bool
+}
+
+fn blend_channel(dst: u32, src: u32, shift: u32, mode: BlendMode, alpha: u32) -> u32 {
+    let d = (dst >> shift) & 0xff;
+    let s = (src >> shift) & 0xff;
+    let mixed = match mode {
+        BlendMode::Normal   => s,
+        BlendMode::Alpha25  => (s + 3 * d) / 4,
+        BlendMode::Alpha50  => (s + d) / 2,
+        BlendMode::Add      => (s + d).min(0xff),
+        BlendMode::Multiply => s * d / 0xff
+    };
+    ((mixed * alpha + d * (0xff - alpha)) / 0xff) << shift
+}
+
+impl MegaFrameBuffer {
+    pub fn new() -> Self {
+        let mut palette = vec![0; 256];
+        palette[MONO_INDEX as usize] = MONO_COLOUR;
+
+        MegaFrameBuffer {
+            palette:   palette,
+            indices:   vec![0; MEGA_WIDTH * MEGA_HEIGHT],
+            back:      vec![0; MEGA_WIDTH * MEGA_HEIGHT],
+            front:     vec![0; MEGA_WIDTH * MEGA_HEIGHT],
+            presented: true
+        }
+    }
+
+    // Colours are loaded from index 1 onwards, index 0 is transparent
+    pub fn load_palette(&mut self, colours: &[u32]) {
+        for (i, colour) in colours.iter().take(255).enumerate() {
+            self.palette[i + 1] = *colour;
+        }
+    }
+
+    // Copy the most recently presented frame, as ARGB, for the frontend
+    pub fn copy_frame(&self, frame: &mut Frame) {
+        frame.width = MEGA_WIDTH;
+        frame.height = MEGA_HEIGHT;
+        match frame.argb {
+            Some(ref mut argb) => argb.clone_from(&self.front),
+            None               => frame.argb = Some(self.front.clone())
+        }
+        frame.dirty = DirtyRegion::all(MEGA_WIDTH, MEGA_HEIGHT);
+    }
+
+    pub fn present(&mut self) {
+        self.front.copy_from_slice(&self.back);
+        self.presented = true;
+        for pixel in self.back.iter_mut() {
+            *pixel = 0;
+        }
+        for index in self.indices.iter_mut() {
+            *index = 0;
+        }
+    }
+
+    // Draw a sprite of palette indices, returning true if it hit a pixel of
+    // the collision colour
+    pub fn draw_sprite(&mut self, x: usize, y: usize, width: usize, height: usize,
+                       data: &[ByteVal], regs: &MegaChipRegs) -> bool {
+        let mut collided = false;
+        let alpha = regs.alpha as u32;
+
+        for sy in 0..height {
+            for sx in 0..width {
+                let (px, py) = (x + sx, y + sy);
+                // Data cut short by the end of memory is transparent
+                let index = data.get(sy * width + sx).cloned().unwrap_or(0);
+                if index == 0 || px >= MEGA_WIDTH || py >= MEGA_HEIGHT {
+                    continue;
+                }
+
+                let off = py * MEGA_WIDTH + px;
+                if self.indices[off] == regs.collision_index {
+                    collided = true;
+                }
+                self.indices[off] = index;
+
+                let (dst, src) = (self.back[off], self.palette[index as usize]);
+                self.back[off] = 0xff00_0000 |
+                    blend_channel(dst, src, 16, regs.blend, alpha) |
+                    blend_channel(dst, src, 8, regs.blend, alpha) |
+                    blend_channel(dst, src, 0, regs.blend, alpha);
+            }
+        }
+
+        collided
+    }
+}
+
+// Plain CHIP-8 drawing, used for font sprites while in MegaChip mode
+impl DisplayInterface for MegaFrameBuffer {
+    fn dimensions(&self) -> (usize, usize) {
+        (MEGA_WIDTH, MEGA_HEIGHT)
+    }
+
+    fn set_dimensions(&mut self, _width: usize, _height: usize) {
+    }
+
+    fn clear(&mut self) {
+        self.present();
+    }
+
+    fn select_planes(&mut self, _planes: ByteVal) {
+    }
+
+    // Scrolling moves the back buffer, filling with transparent pixels
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let old_back = self.back.clone();
+        let old_indices = self.indices.clone();
+
+        for y in 0..MEGA_HEIGHT {
+            for x in 0..MEGA_WIDTH {
+                let (sx, sy) = (x as isize - dx, y as isize - dy);
+                let off = y * MEGA_WIDTH + x;
+                if sx >= 0 && sy >= 0 && (sx as usize) < MEGA_WIDTH && (sy as usize) < MEGA_HEIGHT {
+                    let src = sy as usize * MEGA_WIDTH + sx as usize;
+                    self.back[off] = old_back[src];
+                    self.indices[off] = old_indices[src];
+                } else {
+                    self.back[off] = 0;
+                    self.indices[off] = 0;
+                }
+            }
+        }
+    }
+
+    fn read_pixel(&self, x: ByteVal, y: ByteVal) -> ByteVal {
+        if (y as usize) < MEGA_HEIGHT && self.indices[y as usize * MEGA_WIDTH + x as usize] != 0 { 1 } else { 0 }
+    }
+
+    fn write_pixel(&mut self, x: ByteVal, y: ByteVal, val: ByteVal) {
+        if (y as usize) < MEGA_HEIGHT {
+            let off = y as usize * MEGA_WIDTH + x as usize;
+            self.indices[off] = if val == 0 { 0 } else { MONO_INDEX };
+            self.back[off] = if val == 0 { 0 } else { MONO_COLOUR };
+        }
+    }
+
+    fn write_pixel_xor(&mut self, x: ByteVal, y: ByteVal, val: ByteVal) -> bool {
+        let old = self.read_pixel(x, y);
+        let val = if val == 0 { 0 } else { 1 };
+        self.write_pixel(x, y, old ^ val);
+        old == 1 && val == 1
+    }
+
+    fn write_pixel_row(&mut self, x: ByteVal, y : ByteVal, rowval: ByteVal) {
+        for i in 0..8 {
+            if (x as usize + i) < MEGA_WIDTH {
+                self.write_pixel(x + i as ByteVal, y, (rowval >> (7 - i)) & 1);
+            }
+        }
+    }
+
+    fn write_pixel_row_xor(&mut self, x: ByteVal, y : ByteVal, rowval: ByteVal) -> bool {
+        let mut cleared = false;
+        for i in 0..8 {
+            if (x as usize + i) < MEGA_WIDTH {
+                let pixel_cleared = self.write_pixel_xor(x + i as ByteVal, y, (rowval >> (7 - i)) & 1);
+                cleared = cleared || pixel_cleared;
+            }
+        }
+        cleared
+    }
+
+    fn set_colour_enabled(&mut self, _enabled: bool) {
+    }
+
+    fn cycle_background_colour(&mut self) {
+    }
+
+    fn set_zone_colour(&mut self, _x: usize, _y: usize, _width: usize, _height: usize, _val: ByteVal) {
+    }
+
+    fn background_colour(&self) -> Option<ByteVal> {
+        None
+    }
+
+    fn pixel_colour(&self, _x: ByteVal, _y: ByteVal) -> Option<ByteVal> {
+        None
+    }
+
+    fn set_megachip_enabled(&mut self, _enabled: bool) {
+    }
+
+    fn megachip(&mut self) -> Option<&mut MegaFrameBuffer> {
+        Some(self)
+    }
+
+    // The front buffer is only replaced when the program clears the screen
+    fn vblank(&mut self) -> bool {
+        let presented = self.presented;
+        self.presented = false;
+        presented
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vblank_reports_presented_frames() {
+        let mut mega = MegaFrameBuffer::new();
+        assert!(mega.vblank());
+        assert!(!mega.vblank());
+        mega.present();
+        assert!(mega.vblank());
+        assert!(!mega.vblank());
+    }
+}
@@ -7,50 +7,58 @@ use chip8::types::Addr;
 use chip8::types::ByteVal;
 
 use chip8::core::MemoryInterface;
-use chip8::core::PROG_START_ADDR;
-
-pub const MEM_SIZE: usize = 4096;
+use chip8::variant::MachineVariant;
+use chip8::romdb::{RomDatabase, RomInfo};
 
 pub struct Memory {
-    mem: Vec<ByteVal>
+    mem:      Vec<ByteVal>,
+    variant:  MachineVariant,
+    rom_info: Option<RomInfo>
 }
 
-static FONT: [ByteVal; 80] = [0xF0, 0x90, 0x90, 0x90, 0xF0, 0x20, 0x60, 0x20, 0x20, 0x70,
-                              0xF0, 0x10, 0xF0, 0x80, 0xF0, 0xF0, 0x10, 0xF0, 0x10, 0xF0,
-                              0x90, 0x90, 0xF0, 0x10, 0x10, 0xF0, 0x80, 0xF0, 0x10, 0xF0,
-                              0xF0, 0x80, 0xF0, 0x90, 0xF0, 0xF0, 0x10, 0x20, 0x40, 0x40,
-                              0xF0, 0x90, 0xF0, 0x90, 0xF0, 0xF0, 0x90, 0xF0, 0x10, 0xF0,
-                              0xF0, 0x90, 0xF0, 0x90, 0x90, 0xE0, 0x90, 0xE0, 0x90, 0xE0,
-                              0xF0, 0x80, 0x80, 0x80, 0xF0, 0xE0, 0x90, 0x90, 0x90, 0xE0,
-                              0xF0, 0x80, 0xF0, 0x80, 0xF0, 0xF0, 0x80, 0xF0, 0x80, 0x80];
-
 impl Memory {
     pub fn new() -> Self {
-        let mut mem = vec![0; MEM_SIZE];
-
-        for i in 0..80 {
-            mem[i] = FONT[i];
-        }
-        
         Memory {
-            mem: mem
+            mem:      vec![0; MachineVariant::Chip8.memory_size()],
+            variant:  MachineVariant::Chip8,
+            rom_info: None
         }
     }
+
+    // Variant of the most recently loaded program
+    pub fn variant(&self) -> MachineVariant {
+        self.variant
+    }
+
+    // Database entry for the most recently loaded program, if it is known
+    pub fn rom_info(&self) -> Option<&RomInfo> {
+        self.rom_info.as_ref()
+    }
     
-    pub fn load_file(&mut self, path: &str) -> Result<()> {
+    // Unless a variant is given, it is taken from the ROM database, the file
+    // extension or else detected from the program
+    pub fn load_file(&mut self, path: &str, variant: Option<MachineVariant>, romdb: Option<&RomDatabase>) -> Result<()> {
         let mut f = File::open(path)?;
         let mut byte_vec = Vec::new();
 
         let read_bytes = f.read_to_end(&mut byte_vec)?;
 
-        let memlen = if read_bytes < MEM_SIZE - PROG_START_ADDR {
+        self.rom_info = romdb.and_then(|db| db.lookup(&byte_vec));
+        self.variant = variant.or_else(|| self.rom_info.as_ref().and_then(|info| info.variant))
+                              .or_else(|| MachineVariant::from_extension(path))
+                              .unwrap_or_else(|| MachineVariant::detect(&byte_vec));
+        let load_addr = self.variant.load_addr();
+        let mem_size = self.variant.memory_size();
+        self.mem.resize(mem_size, 0);
+
+        let memlen = if read_bytes < mem_size - load_addr {
             read_bytes
         } else {
-            MEM_SIZE - PROG_START_ADDR
+            mem_size - load_addr
         };
 
         for i in 0..memlen {
-            self.mem[PROG_START_ADDR + i] = byte_vec[i] as ByteVal;
+            self.mem[load_addr + i] = byte_vec[i] as ByteVal;
         }
         
         Ok(())
@@ -58,8 +66,10 @@ impl Memory {
 }
 
 impl MemoryInterface for Memory {
+    // Addresses past the end read as zero, as MegaChip lengths come straight
+    // from the program
     fn read_byte(&self, addr: Addr) -> ByteVal {
-        self.mem[addr as usize]
+        self.mem.get(addr).cloned().unwrap_or(0)
     }
     
     fn write_byte(&mut self, addr: Addr, val: ByteVal) {
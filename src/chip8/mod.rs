@@ -1,40 +1,165 @@
-
 pub mod types;
 pub mod core;
 pub mod memory;
 pub mod display_buffer;
+pub mod timing;
+pub mod cdp1802;
+pub mod vip;
+pub mod variant;
+pub mod megachip;
+pub mod quirks;
+pub mod font;
+pub mod romdb;
+pub mod screenshot;
+pub mod recorder;
+pub mod link;
+
+use chip8::core::{CPU, OpVal, MemoryInterface, RcRefDisplayInterface, RcRefKeyboardInterface, RcRefAudioInterface};
+use chip8::types::{Addr, RegNum};
+use chip8::variant::MachineVariant;
+use chip8::quirks::Quirks;
+use chip8::font::{FONT, FONT_ADDR, BIG_FONT, BIG_FONT_ADDR};
+use chip8::timing::{TimingMode, VipTiming, FrameStats, FramePacer};
 
-use std::time::{Instant, Duration};
-use chip8::core::{CPU, MemoryInterface, RcRefDisplayInterface, RcRefKeyboardInterface};
+pub const DEFAULT_INSTRUCTIONS_PER_FRAME: usize = 10;
 
 pub struct Chip8<'a> {
-    cpu:            CPU<'a>,
-    last_tick_time: Instant,
-    period:         Duration
+    cpu:                    CPU<'a>,
+    timing_mode:            TimingMode,
+    vip_timing:             VipTiming,
+    last_frame_stats:       Option<FrameStats>,
+    instructions_per_frame: usize,
+    instruction_count:      usize,
+    frame_count:            u64,
+    pacer:                  FramePacer
 }
 
 impl<'a> Chip8<'a> {
     pub fn new(mem: &'a mut MemoryInterface,
                display: &'a RcRefDisplayInterface,
-               keyboard: &'a RcRefKeyboardInterface) -> Chip8<'a> {
+               keyboard: &'a RcRefKeyboardInterface,
+               audio: &'a RcRefAudioInterface,
+               variant: MachineVariant) -> Chip8<'a> {
+        for (i, b) in FONT.iter().enumerate() {
+            mem.write_byte(FONT_ADDR + i, *b);
+        }
+        if variant.has_schip_ops() {
+            for (i, b) in BIG_FONT.iter().enumerate() {
+                mem.write_byte(BIG_FONT_ADDR + i, *b);
+            }
+        }
+
+        let (width, height) = variant.display_size();
+        {
+            let mut display = display.borrow_mut();
+            display.set_dimensions(width, height);
+            display.set_colour_enabled(variant.has_colour());
+        }
+
         Chip8 {
-            cpu:            CPU::new(mem, display, keyboard),
-            last_tick_time: Instant::now(),
-            period:         Duration::from_nanos(16666666)
+            cpu:                    CPU::new(mem, display, keyboard, audio, variant),
+            timing_mode:            TimingMode::InstructionCount,
+            vip_timing:             VipTiming::new(),
+            last_frame_stats:       None,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            instruction_count:      0,
+            frame_count:            0,
+            pacer:                  FramePacer::new(false)
         }
     }
-    
+
+    pub fn set_timing_mode(&mut self, timing_mode: TimingMode) {
+        self.timing_mode = timing_mode;
+        self.vip_timing = VipTiming::new();
+    }
+
+    pub fn set_vip_hybrid(&mut self, enabled: bool) {
+        self.cpu.set_vip_hybrid(enabled);
+    }
+
+    // Override the variant's default quirks
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.cpu.set_quirks(quirks);
+    }
+
+    // Only used by `TimingMode::InstructionCount`
+    pub fn set_instructions_per_frame(&mut self, instructions_per_frame: usize) {
+        self.instructions_per_frame = if instructions_per_frame > 0 { instructions_per_frame } else { 1 };
+    }
+
+    // When enabled, each emulated frame is paced to 1/60th of a second of
+    // wall-clock time. Timer values do not depend on this setting.
+    pub fn set_real_time(&mut self, real_time: bool) {
+        self.pacer.set_enabled(real_time);
+    }
+
+    pub fn set_frame_rate(&mut self, frame_rate: u32) {
+        self.pacer.set_frame_rate(frame_rate);
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Cycle usage of the most recently completed frame, when using the VIP
+    // timing model
+    pub fn last_frame_stats(&self) -> Option<FrameStats> {
+        self.last_frame_stats
+    }
 
     pub fn cycle(&mut self) {
+        if self.cpu.halted() {
+            self.end_frame();
+            return;
+        }
+
         let (opval, pc) = self.cpu.fetch_op();
 
         self.cpu.decode_and_execute_op(opval);
 
-        let time = Instant::now();
-        if time.duration_since(self.last_tick_time) > self.period {
-            self.cpu.decrement_timers();
-            self.last_tick_time = time;
+        let frame_complete = match self.timing_mode {
+            TimingMode::InstructionCount => {
+                self.instruction_count += 1;
+                self.instruction_count >= self.instructions_per_frame ||
+                    (opval.0 == 0xd && self.cpu.quirks().display_wait)
+            },
+            TimingMode::Vip => {
+                self.charge_vip_cycles(&opval, pc);
+                self.vip_timing.frame_complete()
+            }
+        };
+
+        if frame_complete {
+            self.end_frame();
         }
     }
 
+    fn charge_vip_cycles(&mut self, opval: &OpVal, pc: Addr) {
+        let OpVal(n0, n1, _, _) = *opval;
+        let skipped = match n0 {
+            0x3 | 0x4 | 0x5 | 0x9 | 0xe => self.cpu.pc() == pc + 4,
+            _                           => false
+        };
+        let vx = self.cpu.vreg(n1 as RegNum);
+
+        self.vip_timing.charge(VipTiming::op_cost(opval, vx, skipped));
+
+        if n0 == 0xd {
+            self.vip_timing.wait_for_vblank();
+        }
+    }
+
+    // Timers tick exactly once per emulated frame, so runs with the same
+    // instructions per frame see identical timer values
+    fn end_frame(&mut self) {
+        self.instruction_count = 0;
+        self.frame_count += 1;
+        if self.timing_mode == TimingMode::Vip {
+            self.last_frame_stats = Some(self.vip_timing.end_frame());
+        }
+        self.cpu.decrement_timers();
+        self.cpu.vblank();
+
+        self.pacer.wait_for_next_frame();
+    }
 }
@@ -0,0 +1,78 @@
+// Behaviours that differ between interpreters for the same instruction
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VY into VX, rather than shifting VX in place
+    pub shift_uses_vy: bool,
+    // FX55/FX65 leave I pointing past the last register
+    pub load_store_increments_i: bool,
+    // BNNN jumps to NNN + VX (with X taken from NNN), rather than NNN + V0
+    pub jump_uses_vx: bool,
+    // 8XY1/8XY2/8XY3 reset VF
+    pub vf_reset: bool,
+    // Sprites wrap around the edges of the display, rather than clipping
+    pub wrap_sprites: bool,
+    // DXYN waits for the next vblank, so at most one sprite is drawn per
+    // frame. Off by default, as it slows most programs down.
+    pub display_wait: bool
+}
+
+impl Quirks {
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name {
+            "chip8"  => Some(Quirks::chip8()),
+            "vip"    => Some(Quirks::vip()),
+            "schip"  => Some(Quirks::schip()),
+            "xochip" => Some(Quirks::xochip()),
+            _        => None
+        }
+    }
+
+    // The emulator's original behaviour, kept as the default for plain
+    // CHIP-8 so ROMs without a database entry run as they always have
+    pub fn chip8() -> Self {
+        Quirks {
+            shift_uses_vy:           false,
+            load_store_increments_i: false,
+            jump_uses_vx:            false,
+            vf_reset:                false,
+            wrap_sprites:            false,
+            display_wait:            false
+        }
+    }
+
+    // Behaviour of the COSMAC VIP interpreter
+    pub fn vip() -> Self {
+        Quirks {
+            shift_uses_vy:           true,
+            load_store_increments_i: true,
+            jump_uses_vx:            false,
+            vf_reset:                true,
+            wrap_sprites:            false,
+            display_wait:            false
+        }
+    }
+
+    // Behaviour of CHIP-48 and SUPER-CHIP on the HP-48
+    pub fn schip() -> Self {
+        Quirks {
+            shift_uses_vy:           false,
+            load_store_increments_i: false,
+            jump_uses_vx:            true,
+            vf_reset:                false,
+            wrap_sprites:            false,
+            display_wait:            false
+        }
+    }
+
+    // Behaviour of XO-CHIP, as implemented by Octo
+    pub fn xochip() -> Self {
+        Quirks {
+            shift_uses_vy:           true,
+            load_store_increments_i: true,
+            jump_uses_vx:            false,
+            vf_reset:                false,
+            wrap_sprites:            true,
+            display_wait:            false
+        }
+    }
+}
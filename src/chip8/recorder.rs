@@ -0,0 +1,253 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use gif;
+
+use chip8::screenshot::unused_name;
+
+// Small displays are scaled up to at least this width when recorded
+const RECORD_WIDTH: usize = 256;
+
+// Frames arrive at 60Hz
+const FRAME_RATE: u64 = 60;
+
+// Frames waiting to be encoded. Once this many are queued, new frames are
+// dropped and their time given to the next frame that fits.
+const QUEUED_FRAMES: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RecordFormat {
+    Gif,
+    // YUV4MPEG2, for piping into video encoders
+    Y4m,
+    // Concatenated binary PPM images
+    Ppm
+}
+
+impl RecordFormat {
+    pub fn from_name(name: &str) -> Option<RecordFormat> {
+        match name {
+            "gif" => Some(RecordFormat::Gif),
+            "y4m" => Some(RecordFormat::Y4m),
+            "ppm" => Some(RecordFormat::Ppm),
+            _     => None
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<RecordFormat> {
+        path.extension().and_then(|ext| ext.to_str()).and_then(RecordFormat::from_name)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match *self {
+            RecordFormat::Gif => "gif",
+            RecordFormat::Y4m => "y4m",
+            RecordFormat::Ppm => "ppm"
+        }
+    }
+}
+
+fn rgb_bytes(frame: &[u32]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(frame.len() * 3);
+    for &pixel in frame {
+        data.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+    }
+    data
+}
+
+fn rgb(pixel: u32) -> (i32, i32, i32) {
+    (((pixel >> 16) & 0xff) as i32, ((pixel >> 8) & 0xff) as i32, (pixel & 0xff) as i32)
+}
+
+enum FrameWriter {
+    Gif(gif::Encoder<BufWriter<File>>, u64),
+    Raw(Box<dyn Write + Send>, RecordFormat)
+}
+
+impl FrameWriter {
+    fn new(path: &Path, format: RecordFormat, size: (usize, usize)) -> io::Result<FrameWriter> {
+        // Y4M and PPM go to standard output for a path of "-"
+        let output = || -> io::Result<Box<dyn Write + Send>> {
+            if path == Path::new("-") {
+                Ok(Box::new(io::stdout()))
+            } else {
+                Ok(Box::new(BufWriter::new(File::create(path)?)))
+            }
+        };
+
+        match format {
+            RecordFormat::Gif => {
+                let file = BufWriter::new(File::create(path)?);
+                let mut encoder = gif::Encoder::new(file, size.0 as u16, size.1 as u16, &[])
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                encoder.set_repeat(gif::Repeat::Infinite).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                Ok(FrameWriter::Gif(encoder, 0))
+            },
+            RecordFormat::Y4m => {
+                let mut output = output()?;
+                write!(output, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444\n", size.0, size.1, FRAME_RATE)?;
+                Ok(FrameWriter::Raw(output, format))
+            },
+            RecordFormat::Ppm => Ok(FrameWriter::Raw(output()?, format))
+        }
+    }
+
+    fn write_frame(&mut self, frame: &[u32], size: (usize, usize)) -> io::Result<()> {
+        match *self {
+            FrameWriter::Gif(ref mut encoder, ref mut count) => {
+                // Delays are in hundredths of a second, so alternate between
+                // 1 and 2 to average 60 frames a second
+                let delay = ((*count + 1) * 100 / FRAME_RATE - *count * 100 / FRAME_RATE) as u16;
+                *count += 1;
+
+                let mut gif_frame = FrameWriter::indexed_frame(frame, size);
+                gif_frame.delay = delay;
+                encoder.write_frame(&gif_frame).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            },
+            FrameWriter::Raw(ref mut output, RecordFormat::Ppm) => {
+                write!(output, "P6\n{} {}\n255\n", size.0, size.1)?;
+                output.write_all(&rgb_bytes(frame))
+            },
+            FrameWriter::Raw(ref mut output, _) => {
+                // BT.601 studio range, with full resolution chroma
+                let yuv: Vec<(i32, i32, i32)> = frame.iter().map(|&pixel| {
+                    let (r, g, b) = rgb(pixel);
+                    (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16,
+                     ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128,
+                     ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128)
+                }).collect();
+                output.write_all(b"FRAME\n")?;
+                output.write_all(&yuv.iter().map(|p| p.0 as u8).collect::<Vec<u8>>())?;
+                output.write_all(&yuv.iter().map(|p| p.1 as u8).collect::<Vec<u8>>())?;
+                output.write_all(&yuv.iter().map(|p| p.2 as u8).collect::<Vec<u8>>())
+            }
+        }
+    }
+
+    // CHIP-8 frames use a handful of colours, so each frame gets an exact
+    // palette of its own. Frames with more colours are quantised.
+    fn indexed_frame(frame: &[u32], size: (usize, usize)) -> gif::Frame<'static> {
+        let mut indices = HashMap::new();
+        let mut palette = Vec::new();
+        let mut pixels = Vec::with_capacity(frame.len());
+        for &pixel in frame {
+            let colour = pixel & 0xffffff;
+            let next = indices.len();
+            let index = *indices.entry(colour).or_insert(next);
+            if index == next {
+                if next == 256 {
+                    return gif::Frame::from_rgb_speed(size.0 as u16, size.1 as u16, &rgb_bytes(frame), 10);
+                }
+                palette.extend_from_slice(&[(colour >> 16) as u8, (colour >> 8) as u8, colour as u8]);
+            }
+            pixels.push(index as u8);
+        }
+
+        let mut gif_frame = gif::Frame::default();
+        gif_frame.width = size.0 as u16;
+        gif_frame.height = size.1 as u16;
+        gif_frame.palette = Some(palette);
+        gif_frame.buffer = Cow::Owned(pixels);
+        gif_frame
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            FrameWriter::Gif(encoder, _) => {
+                encoder.into_inner().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?.flush()
+            },
+            FrameWriter::Raw(mut output, _) => output.flush()
+        }
+    }
+}
+
+// Records frames on a thread of its own, so encoding doesn't hold up the
+// frontend. The recording keeps the size of its first frame, scaled up, and
+// later frames of other sizes are resampled to fit.
+pub struct Recorder {
+    // Each frame is sent with the number of times it is repeated
+    sender:  Option<SyncSender<(Vec<u32>, u64)>>,
+    writer:  Option<JoinHandle<io::Result<()>>>,
+    size:    (usize, usize),
+    started: Instant,
+    frames:  u64,
+    // Copies of dropped frames, added to the next frame that is queued
+    dropped: u64
+}
+
+impl Recorder {
+    pub fn start(path: &Path, format: RecordFormat, display_size: (usize, usize)) -> io::Result<Recorder> {
+        let scale = (RECORD_WIDTH / display_size.0.max(1)).max(1);
+        let size = (display_size.0 * scale, display_size.1 * scale);
+        let mut writer = FrameWriter::new(path, format, size)?;
+
+        let (sender, receiver) = sync_channel::<(Vec<u32>, u64)>(QUEUED_FRAMES);
+        let handle = thread::spawn(move || {
+            for (frame, copies) in receiver {
+                for _ in 0..copies {
+                    writer.write_frame(&frame, size)?;
+                }
+            }
+            writer.finish()
+        });
+
+        Ok(Recorder {
+            sender:  Some(sender),
+            writer:  Some(handle),
+            size:    size,
+            started: Instant::now(),
+            frames:  0,
+            dropped: 0
+        })
+    }
+
+    // Record to a timestamped file in a directory
+    pub fn start_in(dir: &Path, format: RecordFormat, display_size: (usize, usize)) -> io::Result<(Recorder, PathBuf)> {
+        let path = dir.join(format!("{}.{}", unused_name(dir, format.extension()), format.extension()));
+        Recorder::start(&path, format, display_size).map(|recorder| (recorder, path))
+    }
+
+    // Add a frame shown for a number of vblanks. The recording never gets
+    // ahead of the wall clock, so the extra vblanks of a core running faster
+    // than real time, or of a frontend that stalled, are dropped.
+    pub fn add_frame(&mut self, frame: &[u32], size: (usize, usize), vblanks: u64) {
+        let elapsed = self.started.elapsed();
+        let due = elapsed.as_secs() * FRAME_RATE + elapsed.subsec_nanos() as u64 * FRAME_RATE / 1_000_000_000 + 1;
+        let copies = vblanks.min(due.saturating_sub(self.frames));
+        if copies == 0 {
+            return;
+        }
+        self.frames += copies;
+
+        let (width, height) = self.size;
+        let mut scaled = Vec::with_capacity(width * height);
+        for y in 0..height {
+            let row = y * size.1 / height * size.0;
+            for x in 0..width {
+                scaled.push(frame[row + x * size.0 / width]);
+            }
+        }
+        if let Some(ref sender) = self.sender {
+            // A failed writer reports its error when the recording finishes
+            match sender.try_send((scaled, self.dropped + copies)) {
+                Err(TrySendError::Full(_)) => self.dropped += copies,
+                _                          => self.dropped = 0
+            }
+        }
+    }
+
+    // Wait for the queued frames to be written
+    pub fn finish(mut self) -> io::Result<()> {
+        self.sender.take();
+        match self.writer.take() {
+            Some(handle) => handle.join().unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "Recorder failed"))),
+            None         => Ok(())
+        }
+    }
+}
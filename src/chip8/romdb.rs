@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use serde_json;
+use sha1::{Sha1, Digest};
+
+use chip8::types::ByteVal;
+use chip8::variant::MachineVariant;
+use chip8::quirks::Quirks;
+
+// Programs in the format of the community chip-8-database programs.json
+static BUNDLED_PROGRAMS: &'static str = include_str!("../../data/programs.json");
+
+#[derive(Deserialize)]
+struct Program {
+    title:   String,
+    #[serde(default)]
+    authors: Vec<String>,
+    roms:    HashMap<String, Rom>
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rom {
+    #[serde(default)]
+    platforms:        Vec<String>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, QuirkOverrides>,
+    tickrate:         Option<usize>,
+    colors:           Option<Colours>,
+    #[serde(default)]
+    keys:             HashMap<String, ByteVal>
+}
+
+// Quirks that differ from the platform's defaults. The names follow the
+// database, so some have the opposite sense to `Quirks`.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuirkOverrides {
+    shift:                    Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap:                     Option<bool>,
+    jump:                     Option<bool>,
+    logic:                    Option<bool>,
+    vblank:                   Option<bool>
+}
+
+#[derive(Deserialize)]
+struct Colours {
+    #[serde(default)]
+    pixels: Vec<String>
+}
+
+// Suggested keys for actions, such as "up" or "player1Down", mapped to
+// CHIP-8 keys
+pub type GameKeys = HashMap<String, ByteVal>;
+
+// Settings for a known ROM
+#[derive(Clone, Debug)]
+pub struct RomInfo {
+    pub title:                  String,
+    pub authors:                Vec<String>,
+    pub variant:                Option<MachineVariant>,
+    pub quirks:                 Option<Quirks>,
+    pub instructions_per_frame: Option<usize>,
+    // RGB colours for each pixel value, starting with the background
+    pub palette:                Vec<u32>,
+    pub keys:                   GameKeys
+}
+
+pub struct RomDatabase {
+    programs: Vec<Program>,
+    // SHA-1 to index in programs
+    hashes:   HashMap<String, usize>
+}
+
+fn variant_from_platform(platform: &str) -> Option<MachineVariant> {
+    match platform {
+        "originalChip8" | "hybridVIP" | "modernChip8" => Some(MachineVariant::Chip8),
+        "chip8x"                                      => Some(MachineVariant::Chip8X),
+        "chip48"                                      => Some(MachineVariant::Chip48),
+        "superchip1"                                  => Some(MachineVariant::SuperChip10),
+        "superchip"                                   => Some(MachineVariant::SuperChip11),
+        "megachip8"                                   => Some(MachineVariant::MegaChip),
+        "xochip"                                      => Some(MachineVariant::XoChip),
+        _                                             => None
+    }
+}
+
+// Defaults the database's quirk overrides are relative to. All three
+// CHIP-8 platforms run as plain CHIP-8, but the VIP ones behave like the
+// VIP interpreter.
+fn platform_quirks(platform: &str, variant: MachineVariant) -> Quirks {
+    match platform {
+        "originalChip8" | "hybridVIP" => Quirks::vip(),
+        "modernChip8"                 => Quirks::chip8(),
+        _                             => variant.quirks()
+    }
+}
+
+// Colours are written as #rrggbb
+pub fn parse_colour(colour: &str) -> Option<u32> {
+    if colour.starts_with('#') && colour.len() == 7 {
+        u32::from_str_radix(&colour[1..], 16).ok()
+    } else {
+        None
+    }
+}
+
+pub fn sha1_hex(bytes: &[ByteVal]) -> String {
+    Sha1::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl RomDatabase {
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        let programs: Vec<Program> = serde_json::from_str(json)?;
+        let mut hashes = HashMap::new();
+        for (i, program) in programs.iter().enumerate() {
+            for hash in program.roms.keys() {
+                hashes.insert(hash.to_lowercase(), i);
+            }
+        }
+
+        Ok(RomDatabase {
+            programs: programs,
+            hashes:   hashes
+        })
+    }
+
+    pub fn bundled() -> Self {
+        RomDatabase::from_json(BUNDLED_PROGRAMS).expect("Invalid bundled ROM database")
+    }
+
+    pub fn lookup(&self, rom: &[ByteVal]) -> Option<RomInfo> {
+        let hash = sha1_hex(rom);
+        let program = &self.programs[*self.hashes.get(&hash)?];
+        let rom = &program.roms.iter().find(|&(h, _)| h.to_lowercase() == hash)?.1;
+
+        // The first supported platform is the one the ROM was written for
+        let (platform, variant) = rom.platforms.iter()
+            .filter_map(|p| variant_from_platform(p).map(|v| (p, v)))
+            .next()
+            .map_or((None, None), |(p, v)| (Some(p), Some(v)));
+
+        let quirks = match (platform, variant, platform.and_then(|p| rom.quirky_platforms.get(p))) {
+            (Some(platform), Some(variant), Some(overrides)) => {
+                let mut quirks = platform_quirks(platform, variant);
+                if let Some(shift) = overrides.shift { quirks.shift_uses_vy = !shift; }
+                if let Some(leave) = overrides.memory_leave_i_unchanged { quirks.load_store_increments_i = !leave; }
+                if let Some(wrap) = overrides.wrap { quirks.wrap_sprites = wrap; }
+                if let Some(jump) = overrides.jump { quirks.jump_uses_vx = jump; }
+                if let Some(logic) = overrides.logic { quirks.vf_reset = logic; }
+                if let Some(vblank) = overrides.vblank { quirks.display_wait = vblank; }
+                Some(quirks)
+            },
+            _ => None
+        };
+
+        Some(RomInfo {
+            title:                  program.title.clone(),
+            authors:                program.authors.clone(),
+            variant:                variant,
+            quirks:                 quirks,
+            instructions_per_frame: rom.tickrate,
+            palette:                rom.colors.as_ref()
+                                       .map(|c| c.pixels.iter().filter_map(|p| parse_colour(p)).collect())
+                                       .unwrap_or_default(),
+            keys:                   rom.keys.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A database with the given ROM, listed for one platform with the
+    // shift quirk overridden
+    fn lookup_quirks(rom: &[ByteVal], platform: &str) -> Option<Quirks> {
+        let json = format!(r#"[{{"title": "Test", "roms": {{"{}": {{
+                                   "platforms": ["{}"],
+                                   "quirkyPlatforms": {{"{}": {{"shift": true}}}}
+                               }}}}}}]"#, sha1_hex(rom), platform, platform);
+        RomDatabase::from_json(&json).unwrap().lookup(rom).unwrap().quirks
+    }
+
+    #[test]
+    fn overrides_start_from_the_platform_quirks() {
+        let rom = [0x00, 0xe0];
+
+        let mut vip = Quirks::vip();
+        vip.shift_uses_vy = false;
+        assert_eq!(lookup_quirks(&rom, "originalChip8"), Some(vip));
+        assert_eq!(lookup_quirks(&rom, "hybridVIP"), Some(vip));
+        assert_eq!(lookup_quirks(&rom, "modernChip8"), Some(Quirks::chip8()));
+
+        let mut xochip = Quirks::xochip();
+        xochip.shift_uses_vy = false;
+        assert_eq!(lookup_quirks(&rom, "xochip"), Some(xochip));
+    }
+}
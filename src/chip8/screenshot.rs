@@ -0,0 +1,74 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use png;
+
+// Write an ARGB frame as an RGB PNG, with each pixel drawn as a
+// scale x scale block
+pub fn write_png<P: AsRef<Path>>(path: P, frame: &[u32], size: (usize, usize), scale: usize) -> io::Result<()> {
+    let scale = scale.max(1);
+    let (width, height) = (size.0 * scale, size.1 * scale);
+
+    let mut data = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = frame[(y / scale) * size.0 + x / scale];
+            data.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+        }
+    }
+
+    let file = File::create(path)?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    writer.write_image_data(&data).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+// UTC date and time as YYYYMMDD-HHMMSS
+pub fn timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, time) = ((secs / 86400) as i64, secs % 86400);
+
+    // Civil date from days since the epoch, after Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}{:02}{:02}-{:02}{:02}{:02}", year, month, day, time / 3600, (time / 60) % 60, time % 60)
+}
+
+// A timestamped name for a new file in dir. Names taken within the same
+// second are told apart by a counter.
+pub fn unused_name(dir: &Path, extension: &str) -> String {
+    let stem = format!("chip8-{}", timestamp());
+    let mut name = stem.clone();
+    let mut count = 1;
+    while dir.join(format!("{}.{}", name, extension)).exists() {
+        count += 1;
+        name = format!("{}-{}", stem, count);
+    }
+    name
+}
+
+// Save a frame at its native resolution and, when larger, at the scale it
+// is shown at, returning the files written
+pub fn save_screenshots(dir: &Path, frame: &[u32], size: (usize, usize), scale: usize) -> io::Result<Vec<PathBuf>> {
+    let name = unused_name(dir, "png");
+    let mut paths = vec![dir.join(format!("{}.png", name))];
+    if scale > 1 {
+        paths.push(dir.join(format!("{}-x{}.png", name, scale)));
+    }
+    for (i, path) in paths.iter().enumerate() {
+        write_png(path, frame, size, if i == 0 { 1 } else { scale })?;
+    }
+    Ok(paths)
+}
@@ -0,0 +1,171 @@
+use std::thread;
+use std::time::{Instant, Duration};
+
+use chip8::core::OpVal;
+use chip8::types::ByteVal;
+
+// The COSMAC VIP clocks its 1802 at 1.7609 MHz, with 8 clocks per machine
+// cycle. The CDP1861 produces a 60Hz frame of 262 lines, 14 machine cycles
+// each.
+pub const VIP_FRAME_CYCLES: u32 = 262 * 14;
+
+// Display DMA steals 8 machine cycles on each of the 128 visible lines, and
+// the interpreter's interrupt routine runs once per frame.
+pub const VIP_DMA_CYCLES: u32 = 128 * 8;
+pub const VIP_INTERRUPT_CYCLES: u32 = 46;
+
+// Cycles left over for the CHIP-8 interpreter in each frame
+pub const VIP_FRAME_BUDGET: u32 = VIP_FRAME_CYCLES - VIP_DMA_CYCLES - VIP_INTERRUPT_CYCLES;
+
+// Cost of the interpreter's fetch and dispatch loop, paid by every instruction
+const VIP_FETCH_CYCLES: u32 = 40;
+
+// Extra cost of a taken skip
+const VIP_SKIP_CYCLES: u32 = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TimingMode {
+    // A fixed number of instructions per frame
+    InstructionCount,
+    // Each instruction is charged its COSMAC VIP machine cycle cost
+    Vip
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct FrameStats {
+    pub instructions: u32,
+    pub cycles_used:  u32,
+    pub cycles_idle:  u32,
+    pub budget:       u32
+}
+
+pub struct VipTiming {
+    instructions: u32,
+    cycles_used:  u32,
+    stalled:      bool
+}
+
+impl VipTiming {
+    pub fn new() -> Self {
+        VipTiming {
+            instructions: 0,
+            cycles_used:  0,
+            stalled:      false
+        }
+    }
+
+    // Approximate machine cycles taken by the VIP interpreter to execute an
+    // instruction. `vx` is the value of VX after execution and `skipped`
+    // is true if the instruction skipped the following one.
+    pub fn op_cost(opval: &OpVal, vx: ByteVal, skipped: bool) -> u32 {
+        let OpVal(n0, n1, n2, n3) = *opval;
+
+        let cost = match (n0, n1, n2, n3) {
+            (0x0, 0x0, 0xe, 0x0) => 3078,
+            (0x0, 0x0, 0xe, 0xe) => 10,
+            (0x0,   _,   _,   _) => 26,
+            (0x1,   _,   _,   _) => 12,
+            (0x2,   _,   _,   _) => 26,
+            (0x3,   _,   _,   _) => 10,
+            (0x4,   _,   _,   _) => 10,
+            (0x5,   _,   _,   _) => 14,
+            (0x6,   _,   _,   _) => 6,
+            (0x7,   _,   _,   _) => 10,
+            (0x8,   _,   _, 0x0) => 12,
+            (0x8,   _,   _,   _) => 44,
+            (0x9,   _,   _,   _) => 14,
+            (0xa,   _,   _,   _) => 12,
+            (0xb,   _,   _,   _) => 22,
+            (0xc,   _,   _,   _) => 36,
+            (0xd,   _,   _,   n) => 26 + 46 * n as u32,
+            (0xe,   _,   _,   _) => 14,
+            (0xf,   _, 0x0, 0xa) => 19,
+            (0xf,   _, 0x1, 0xe) => 16,
+            (0xf,   _, 0x2, 0x9) => 16,
+            // BCD conversion is done by repeated subtraction
+            (0xf,   _, 0x3, 0x3) => 84 + 16 * ((vx / 100) + (vx / 10) % 10 + vx % 10) as u32,
+            (0xf,   x, 0x5, 0x5) => 14 + 14 * (x as u32 + 1),
+            (0xf,   x, 0x6, 0x5) => 14 + 14 * (x as u32 + 1),
+            (0xf,   _,   _,   _) => 10,
+            (  _,   _,   _,   _) => 0
+        };
+
+        VIP_FETCH_CYCLES + cost + if skipped { VIP_SKIP_CYCLES } else { 0 }
+    }
+
+    pub fn charge(&mut self, cycles: u32) {
+        self.instructions += 1;
+        self.cycles_used += cycles;
+    }
+
+    // DXYN on the VIP waits for the display interrupt before drawing, so the
+    // remainder of the frame is spent idle
+    pub fn wait_for_vblank(&mut self) {
+        self.stalled = true;
+    }
+
+    pub fn frame_complete(&self) -> bool {
+        self.stalled || self.cycles_used >= VIP_FRAME_BUDGET
+    }
+
+    // Close the current frame, carrying any overrun into the next one
+    pub fn end_frame(&mut self) -> FrameStats {
+        let stats = FrameStats {
+            instructions: self.instructions,
+            cycles_used:  self.cycles_used,
+            cycles_idle:  if self.cycles_used < VIP_FRAME_BUDGET { VIP_FRAME_BUDGET - self.cycles_used } else { 0 },
+            budget:       VIP_FRAME_BUDGET
+        };
+
+        self.cycles_used = if self.cycles_used > VIP_FRAME_BUDGET { self.cycles_used - VIP_FRAME_BUDGET } else { 0 };
+        self.instructions = 0;
+        self.stalled = false;
+
+        stats
+    }
+}
+
+// Paces emulated frames to 60Hz of wall-clock time
+pub struct FramePacer {
+    enabled:         bool,
+    next_frame_time: Instant,
+    period:          Duration
+}
+
+impl FramePacer {
+    pub fn new(enabled: bool) -> Self {
+        let period = Duration::from_nanos(16666666);
+        FramePacer {
+            enabled:         enabled,
+            next_frame_time: Instant::now() + period,
+            period:          period
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        self.next_frame_time = Instant::now() + self.period;
+    }
+
+    // Frames per second of wall-clock time, 60 by default
+    pub fn set_frame_rate(&mut self, frame_rate: u32) {
+        self.period = Duration::from_nanos(1_000_000_000 / frame_rate.max(1) as u64);
+        self.next_frame_time = Instant::now() + self.period;
+    }
+
+    pub fn wait_for_next_frame(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        let time = Instant::now();
+        if self.next_frame_time > time {
+            thread::sleep(self.next_frame_time - time);
+            self.next_frame_time += self.period;
+        } else {
+            // Fell behind (e.g. the process was suspended), so start
+            // pacing afresh rather than racing to catch up
+            self.next_frame_time = time + self.period;
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use std::path::Path;
+
+use chip8::types::{Addr, ByteVal};
+use chip8::quirks::Quirks;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MachineVariant {
+    // Original COSMAC VIP CHIP-8
+    Chip8,
+    // CHIP-48 on the HP-48
+    Chip48,
+    // SUPER-CHIP 1.0, adding a 128x64 mode and 16x16 sprites
+    SuperChip10,
+    // SUPER-CHIP 1.1, adding scrolling
+    SuperChip11,
+    // XO-CHIP, adding 64K of memory, two bitplanes and audio patterns
+    XoChip,
+    // Two-page CHIP-8 HiRes with a 64x64 display
+    HiRes,
+    // CHIP-8X, with VP-590 colour and a second VP-580 keypad
+    Chip8X,
+    // MegaChip-8, adding a 256x192 palette-based display and sampled sound
+    MegaChip
+}
+
+// HiRes programs start with a jump into the patched interpreter
+const HIRES_SIGNATURE: [ByteVal; 2] = [0x12, 0x60];
+
+// Number of variant-specific instructions a program must contain before the
+// scan trusts them over the chance of them being sprite data
+const DETECT_THRESHOLD: usize = 2;
+
+impl MachineVariant {
+    // Identify the variant from a program image. Programs are scanned for
+    // instructions that only exist in one variant, preferring the richest
+    // instruction set found.
+    pub fn detect(program: &[ByteVal]) -> MachineVariant {
+        if program.starts_with(&HIRES_SIGNATURE) {
+            return MachineVariant::HiRes;
+        }
+
+        let (mut xochip, mut megachip, mut schip, mut chip8x) = (0, 0, 0, 0);
+        for op in program.chunks(2).filter(|op| op.len() == 2) {
+            let (hi, lo) = (op[0], op[1]);
+            match (hi >> 4, hi & 0xf, lo >> 4, lo & 0xf) {
+                (0xf, 0x0, 0x0, 0x0) | (0xf,   _, 0x0, 0x1) | (0xf, 0x0, 0x0, 0x2) |
+                (0xf,   _, 0x3, 0xa) | (0x5,   _,   _, 0x2) | (0x5,   _,   _, 0x3) |
+                (0x0, 0x0, 0xd,   _) => xochip += 1,
+                (0x0, 0x0, 0x1, 0x1) => megachip += 1,
+                (0x0, 0x0, 0xf, 0xb) | (0x0, 0x0, 0xf, 0xc) | (0x0, 0x0, 0xf, 0xd) |
+                (0x0, 0x0, 0xf, 0xe) | (0x0, 0x0, 0xf, 0xf) | (0xf,   _, 0x3, 0x0) |
+                (0xf,   _, 0x7, 0x5) | (0xf,   _, 0x8, 0x5) => schip += 1,
+                (0x0, 0x2, 0xa, 0x0) | (0x5,   _,   _, 0x1) | (0xe,   _, 0xf, 0x2) |
+                (0xe,   _, 0xf, 0x5) | (0xf,   _, 0xf, 0x8) | (0xf,   _, 0xf, 0xb) => chip8x += 1,
+                _ => {}
+            }
+        }
+
+        if megachip >= 1 && schip >= DETECT_THRESHOLD {
+            MachineVariant::MegaChip
+        } else if xochip >= DETECT_THRESHOLD {
+            MachineVariant::XoChip
+        } else if schip >= DETECT_THRESHOLD {
+            MachineVariant::SuperChip11
+        } else if chip8x >= DETECT_THRESHOLD {
+            MachineVariant::Chip8X
+        } else {
+            MachineVariant::Chip8
+        }
+    }
+
+    pub fn from_extension(path: &str) -> Option<MachineVariant> {
+        let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+        match extension.map(|ext| ext.to_lowercase()) {
+            Some(ref ext) if ext == "ch8" => Some(MachineVariant::Chip8),
+            Some(ref ext) if ext == "c8x" => Some(MachineVariant::Chip8X),
+            Some(ref ext) if ext == "sc8" => Some(MachineVariant::SuperChip11),
+            Some(ref ext) if ext == "xo8" => Some(MachineVariant::XoChip),
+            Some(ref ext) if ext == "mc8" => Some(MachineVariant::MegaChip),
+            _                             => None
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MachineVariant> {
+        match name {
+            "chip8"    => Some(MachineVariant::Chip8),
+            "chip48"   => Some(MachineVariant::Chip48),
+            "schip1.0" => Some(MachineVariant::SuperChip10),
+            "schip1.1" => Some(MachineVariant::SuperChip11),
+            "xochip"   => Some(MachineVariant::XoChip),
+            "hires"    => Some(MachineVariant::HiRes),
+            "chip8x"   => Some(MachineVariant::Chip8X),
+            "megachip" => Some(MachineVariant::MegaChip),
+            _          => None
+        }
+    }
+
+    // Address the program image is loaded at
+    pub fn load_addr(&self) -> Addr {
+        match *self {
+            MachineVariant::Chip8X => 0x300,
+            _                      => 0x200
+        }
+    }
+
+    // Address execution begins at
+    pub fn start_addr(&self) -> Addr {
+        match *self {
+            MachineVariant::HiRes => 0x2c0,
+            _                     => self.load_addr()
+        }
+    }
+
+    // Display size at reset
+    pub fn display_size(&self) -> (usize, usize) {
+        match *self {
+            MachineVariant::HiRes => (64, 64),
+            _                     => (64, 32)
+        }
+    }
+
+    // Display size of the SUPER-CHIP high resolution mode
+    pub fn hires_display_size(&self) -> (usize, usize) {
+        (128, 64)
+    }
+
+    pub fn memory_size(&self) -> usize {
+        match *self {
+            MachineVariant::XoChip   => 0x10000,
+            MachineVariant::MegaChip => 0x1000000,
+            _                        => 0x1000
+        }
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        match *self {
+            MachineVariant::Chip48 | MachineVariant::SuperChip10 |
+            MachineVariant::SuperChip11 | MachineVariant::MegaChip => Quirks::schip(),
+            MachineVariant::XoChip                                 => Quirks::xochip(),
+            MachineVariant::Chip8                                  => Quirks::chip8(),
+            _                                                      => Quirks::vip()
+        }
+    }
+
+    pub fn has_colour(&self) -> bool {
+        *self == MachineVariant::Chip8X
+    }
+
+    // 00FE/00FF resolution switching, 16x16 sprites, the big font and RPL
+    // flags
+    pub fn has_schip_ops(&self) -> bool {
+        match *self {
+            MachineVariant::SuperChip10 | MachineVariant::SuperChip11 |
+            MachineVariant::XoChip | MachineVariant::MegaChip => true,
+            _                                                 => false
+        }
+    }
+
+    pub fn has_scroll_ops(&self) -> bool {
+        self.has_schip_ops() && *self != MachineVariant::SuperChip10
+    }
+
+    pub fn has_xochip_ops(&self) -> bool {
+        *self == MachineVariant::XoChip
+    }
+}
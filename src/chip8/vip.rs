@@ -0,0 +1,220 @@
+use std::fs::File;
+use std::io::Read;
+use std::io::Result;
+
+use chip8::cdp1802::{Cdp1802, Cdp1802Bus};
+use chip8::core::{MemoryInterface, RcRefDisplayInterface, RcRefKeyboardInterface};
+use chip8::timing::FramePacer;
+use chip8::types::{Addr, ByteVal};
+use chip8::variant::MachineVariant;
+
+pub const VIP_RAM_SIZE: usize = 4096;
+pub const VIP_ROM_SIZE: usize = 512;
+
+// Page holding the interpreter's display buffer in a 4K machine
+const VIP_TOP_PAGE: u16 = 0x0f;
+
+// CDP1861 frame layout, in lines of 14 machine cycles
+const PIXIE_LINES: usize = 262;
+const PIXIE_LINE_CYCLES: u32 = 14;
+const PIXIE_DMA_CYCLES: u32 = 8;
+const PIXIE_INTERRUPT_LINE: usize = 62;
+const PIXIE_DISPLAY_START: usize = 64;
+const PIXIE_DISPLAY_LINES: usize = 128;
+
+const DISPLAY_WIDTH_BYTES: usize = 8;
+const DISPLAY_ROWS: usize = 32;
+
+// RAM is mirrored across the lower half of the address space and the
+// monitor ROM across the upper half
+pub struct VipMemory {
+    ram: Vec<ByteVal>,
+    rom: Vec<ByteVal>
+}
+
+impl VipMemory {
+    pub fn new() -> Self {
+        VipMemory {
+            ram: vec![0; VIP_RAM_SIZE],
+            rom: vec![0; VIP_ROM_SIZE]
+        }
+    }
+
+    fn read_file(path: &str) -> Result<Vec<ByteVal>> {
+        let mut f = File::open(path)?;
+        let mut byte_vec = Vec::new();
+        f.read_to_end(&mut byte_vec)?;
+        Ok(byte_vec)
+    }
+
+    pub fn load_ram(&mut self, path: &str, addr: Addr) -> Result<()> {
+        let byte_vec = VipMemory::read_file(path)?;
+        for (i, byte) in byte_vec.iter().take(VIP_RAM_SIZE - addr).enumerate() {
+            self.ram[addr + i] = *byte;
+        }
+        Ok(())
+    }
+
+    pub fn load_rom(&mut self, path: &str) -> Result<()> {
+        let byte_vec = VipMemory::read_file(path)?;
+        for (i, byte) in byte_vec.iter().take(VIP_ROM_SIZE).enumerate() {
+            self.rom[i] = *byte;
+        }
+        Ok(())
+    }
+
+    // The interpreter image goes at the bottom of RAM, followed by the
+    // CHIP-8 program. Without a monitor ROM, FX29 has no font to point at.
+    pub fn load_images(&mut self, interpreter: &str, monitor: Option<&str>, program: &str) -> Result<()> {
+        self.load_ram(interpreter, 0)?;
+        if let Some(monitor) = monitor {
+            self.load_rom(monitor)?;
+        }
+        self.load_ram(program, MachineVariant::Chip8.load_addr())
+    }
+}
+
+impl MemoryInterface for VipMemory {
+    fn read_byte(&self, addr: Addr) -> ByteVal {
+        if addr & 0x8000 != 0 {
+            self.rom[addr % VIP_ROM_SIZE]
+        } else {
+            self.ram[addr % VIP_RAM_SIZE]
+        }
+    }
+
+    fn write_byte(&mut self, addr: Addr, val: ByteVal) {
+        if addr & 0x8000 == 0 {
+            self.ram[addr % VIP_RAM_SIZE] = val;
+        }
+    }
+}
+
+// Peripherals on the 1802's I/O lines: the CDP1861 (INP 1 turns the display
+// on, OUT 1 turns it off, EF1 flags the display edges) and the hex keypad
+// latch (OUT 2 selects a key, EF3 reports whether it is pressed)
+struct VipBus<'a> {
+    keyboard:        &'a RcRefKeyboardInterface,
+    key_latch:       ByteVal,
+    display_enabled: bool,
+    line:            usize
+}
+
+impl<'a> Cdp1802Bus for VipBus<'a> {
+    fn output(&mut self, port: ByteVal, val: ByteVal) {
+        match port {
+            1 => self.display_enabled = false,
+            2 => self.key_latch = val & 0xf,
+            _ => {}
+        };
+    }
+
+    fn input(&mut self, port: ByteVal) -> ByteVal {
+        if port == 1 {
+            self.display_enabled = true;
+        }
+        0
+    }
+
+    fn ef(&self, line: ByteVal) -> bool {
+        match line {
+            1 => {
+                let end = PIXIE_DISPLAY_START + PIXIE_DISPLAY_LINES;
+                (self.line >= PIXIE_DISPLAY_START - 4 && self.line < PIXIE_DISPLAY_START) ||
+                    (self.line >= end - 4 && self.line < end)
+            },
+            3 => self.keyboard.borrow().key_pressed(self.key_latch),
+            _ => false
+        }
+    }
+}
+
+// Low-level COSMAC VIP: a CDP1802 running the original CHIP-8 interpreter,
+// with its display fetched by CDP1861 DMA
+pub struct VipSystem<'a> {
+    cpu:          Cdp1802,
+    mem:          &'a mut VipMemory,
+    display:      &'a RcRefDisplayInterface,
+    bus:          VipBus<'a>,
+    lines:        Vec<[ByteVal; DISPLAY_WIDTH_BYTES]>,
+    cycle_credit: i32,
+    pacer:        FramePacer
+}
+
+impl<'a> VipSystem<'a> {
+    pub fn new(mem: &'a mut VipMemory,
+               display: &'a RcRefDisplayInterface,
+               keyboard: &'a RcRefKeyboardInterface) -> VipSystem<'a> {
+        // The monitor hands over to RAM with R1.1 holding the top page
+        let mut cpu = Cdp1802::new();
+        cpu.r[1] = VIP_TOP_PAGE << 8;
+
+        VipSystem {
+            cpu:          cpu,
+            mem:          mem,
+            display:      display,
+            bus:          VipBus {
+                keyboard:        keyboard,
+                key_latch:       0,
+                display_enabled: false,
+                line:            0
+            },
+            lines:        vec![[0; DISPLAY_WIDTH_BYTES]; PIXIE_DISPLAY_LINES],
+            cycle_credit: 0,
+            pacer:        FramePacer::new(false)
+        }
+    }
+
+    pub fn set_real_time(&mut self, real_time: bool) {
+        self.pacer.set_enabled(real_time);
+    }
+
+    fn run_cpu(&mut self, cycles: u32) {
+        self.cycle_credit += cycles as i32;
+        while self.cycle_credit > 0 {
+            self.cycle_credit -= self.cpu.step(self.mem, &mut self.bus) as i32;
+        }
+    }
+
+    // Emulate one 60Hz frame
+    pub fn run_frame(&mut self) {
+        for line in 0..PIXIE_LINES {
+            self.bus.line = line;
+
+            if line == PIXIE_INTERRUPT_LINE && self.bus.display_enabled {
+                self.cycle_credit -= self.cpu.interrupt() as i32;
+            }
+
+            let visible = line >= PIXIE_DISPLAY_START && line < PIXIE_DISPLAY_START + PIXIE_DISPLAY_LINES;
+            if visible && self.bus.display_enabled {
+                self.run_cpu(PIXIE_LINE_CYCLES - PIXIE_DMA_CYCLES);
+                for i in 0..DISPLAY_WIDTH_BYTES {
+                    self.lines[line - PIXIE_DISPLAY_START][i] = self.cpu.dma_out(self.mem);
+                }
+            } else {
+                self.run_cpu(PIXIE_LINE_CYCLES);
+            }
+        }
+
+        self.update_display();
+        self.pacer.wait_for_next_frame();
+    }
+
+    // The interpreter repeats each row over 4 scan lines, sample the middle
+    // of each group so that slight timing misalignment doesn't matter
+    fn update_display(&mut self) {
+        let mut display = self.display.borrow_mut();
+        if !self.bus.display_enabled {
+            (*display).clear();
+        } else {
+            let repeat = PIXIE_DISPLAY_LINES / DISPLAY_ROWS;
+            for y in 0..DISPLAY_ROWS {
+                let line = &self.lines[y * repeat + repeat / 2];
+                for i in 0..DISPLAY_WIDTH_BYTES {
+                    (*display).write_pixel_row((i * 8) as ByteVal, y as ByteVal, line[i]);
+                }
+            }
+        }
+        (*display).vblank();
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use dirs;
+use toml;
+
+use chip8::types::ByteVal;
+use chip8::quirks::Quirks;
+use chip8::romdb::sha1_hex;
+use backends::keymap::{Keymap, DEFAULT_PRESET};
+
+const CONFIG_DIR: &'static str = "rs-chip8-emu";
+const CONFIG_FILE: &'static str = "config.toml";
+
+// Every setting is optional, so that sections can be layered with only the
+// settings they give taking effect
+
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct VideoConfig {
+    pub pixel_width:  Option<usize>,
+    pub pixel_height: Option<usize>,
+    // Colours are written as #rrggbb
+    pub foreground:   Option<String>,
+    pub background:   Option<String>,
+    // A built-in palette or one defined in palettes
+    pub palette:      Option<String>,
+    // Up to four colours each, for the background then planes 1, 2 and both
+    pub palettes:     HashMap<String, Vec<String>>,
+    // Show a clickable keypad beside the display
+    pub keypad:       Option<bool>,
+    // Show the keys the program has recently tested
+    pub key_hints:    Option<bool>,
+    pub fullscreen:   Option<bool>,
+    // "integer" or "fractional"
+    pub scaling:      Option<String>,
+    // Phosphor persistence: "off", "decay" or "blend"
+    pub persistence:  Option<String>,
+    // Fraction of an unlit pixel's colour kept each frame when decaying
+    pub decay:        Option<f32>,
+    // Number of frames averaged when blending
+    pub blend_frames: Option<usize>,
+    // CRT effects: "scanlines", "grid", "glow", "curvature" and "vignette"
+    pub crt:          Option<Vec<String>>,
+    // Texels per display pixel for the CRT effects, chosen by display size
+    // when not given
+    pub crt_scale:    Option<usize>,
+    // Where screenshots are saved, the current directory by default
+    pub screenshot_dir: Option<String>,
+    // Format of recordings started with the hotkey: "gif", "y4m" or "ppm"
+    pub record_format: Option<String>,
+    // Pixels per character cell in the text UI: "auto", "half",
+    // "quadrant", "sextant" or "braille"
+    pub text_mode:    Option<String>
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct AudioConfig {
+    pub enabled: Option<bool>,
+    // 0.0 to 1.0
+    pub volume:  Option<f32>
+}
+
+// One host key name, or a list of them
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum KeyBinding {
+    One(String),
+    Many(Vec<String>)
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct GamepadConfig {
+    pub deadzone: Option<i16>,
+    // Button or stick direction, e.g. "dpup" or "leftx-", to CHIP-8 key
+    pub buttons:  HashMap<String, String>
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct InputConfig {
+    // Keymap preset, with any keys below rebound on top
+    pub preset:  Option<String>,
+    // CHIP-8 key, as a hex digit, to host key names
+    pub keys:    HashMap<String, KeyBinding>,
+    // Bindings for player 1, then player 2
+    pub gamepad: Vec<GamepadConfig>
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct TimingConfig {
+    pub instructions_per_frame: Option<usize>,
+    pub frame_rate:             Option<u32>,
+    pub real_time:              Option<bool>,
+    // "instructions" or "vip"
+    pub model:                  Option<String>
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct QuirksConfig {
+    // "vip", "schip" or "xochip", with any of the flags below on top
+    pub preset:                  Option<String>,
+    pub shift_uses_vy:           Option<bool>,
+    pub load_store_increments_i: Option<bool>,
+    pub jump_uses_vx:            Option<bool>,
+    pub vf_reset:                Option<bool>,
+    pub wrap_sprites:            Option<bool>,
+    pub display_wait:            Option<bool>
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(default)]
+pub struct Settings {
+    pub video:  VideoConfig,
+    pub audio:  AudioConfig,
+    pub input:  InputConfig,
+    pub timing: TimingConfig,
+    pub quirks: QuirksConfig
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Config {
+    #[serde(flatten)]
+    pub global: Settings,
+    // Overrides for a ROM, keyed by file name or SHA-1
+    pub rom:    HashMap<String, Settings>
+}
+
+impl VideoConfig {
+    fn merge(&mut self, other: &VideoConfig) {
+        self.pixel_width = other.pixel_width.or(self.pixel_width);
+        self.pixel_height = other.pixel_height.or(self.pixel_height);
+        self.foreground = other.foreground.clone().or(self.foreground.take());
+        self.background = other.background.clone().or(self.background.take());
+        self.palette = other.palette.clone().or(self.palette.take());
+        for (name, colours) in other.palettes.iter() {
+            self.palettes.insert(name.clone(), colours.clone());
+        }
+        self.keypad = other.keypad.or(self.keypad);
+        self.key_hints = other.key_hints.or(self.key_hints);
+        self.fullscreen = other.fullscreen.or(self.fullscreen);
+        self.scaling = other.scaling.clone().or(self.scaling.take());
+        self.persistence = other.persistence.clone().or(self.persistence.take());
+        self.decay = other.decay.or(self.decay);
+        self.blend_frames = other.blend_frames.or(self.blend_frames);
+        self.crt = other.crt.clone().or(self.crt.take());
+        self.crt_scale = other.crt_scale.or(self.crt_scale);
+        self.screenshot_dir = other.screenshot_dir.clone().or(self.screenshot_dir.take());
+        self.record_format = other.record_format.clone().or(self.record_format.take());
+        self.text_mode = other.text_mode.clone().or(self.text_mode.take());
+    }
+}
+
+impl AudioConfig {
+    fn merge(&mut self, other: &AudioConfig) {
+        self.enabled = other.enabled.or(self.enabled);
+        self.volume = other.volume.or(self.volume);
+    }
+}
+
+impl InputConfig {
+    fn merge(&mut self, other: &InputConfig) {
+        if other.preset.is_some() {
+            self.preset = other.preset.clone();
+            self.keys.clear();
+        }
+        for (key, binding) in other.keys.iter() {
+            self.keys.insert(key.clone(), binding.clone());
+        }
+        if !other.gamepad.is_empty() {
+            self.gamepad = other.gamepad.clone();
+        }
+    }
+
+    pub fn keymap(&self) -> Keymap {
+        let preset = self.preset.as_ref().map(|p| p.as_str()).unwrap_or(DEFAULT_PRESET);
+        let mut keymap = Keymap::preset(preset).unwrap_or_else(|| {
+            eprintln!("Unknown keymap {}", preset);
+            Keymap::preset(DEFAULT_PRESET).unwrap()
+        });
+
+        for (key, binding) in self.keys.iter() {
+            let names = match *binding {
+                KeyBinding::One(ref name)   => vec![name.clone()],
+                KeyBinding::Many(ref names) => names.clone()
+            };
+            match ByteVal::from_str_radix(key, 16) {
+                Ok(key) if key < 16 => keymap.bind(key, &names),
+                _                   => eprintln!("Ignoring binding for unknown key {}", key)
+            }
+        }
+        keymap
+    }
+}
+
+impl TimingConfig {
+    fn merge(&mut self, other: &TimingConfig) {
+        self.instructions_per_frame = other.instructions_per_frame.or(self.instructions_per_frame);
+        self.frame_rate = other.frame_rate.or(self.frame_rate);
+        self.real_time = other.real_time.or(self.real_time);
+        self.model = other.model.clone().or(self.model.take());
+    }
+}
+
+impl QuirksConfig {
+    fn merge(&mut self, other: &QuirksConfig) {
+        if other.preset.is_some() {
+            // A new preset replaces the flags given with the old one
+            *self = QuirksConfig::default();
+        }
+        self.preset = other.preset.clone().or(self.preset.take());
+        self.shift_uses_vy = other.shift_uses_vy.or(self.shift_uses_vy);
+        self.load_store_increments_i = other.load_store_increments_i.or(self.load_store_increments_i);
+        self.jump_uses_vx = other.jump_uses_vx.or(self.jump_uses_vx);
+        self.vf_reset = other.vf_reset.or(self.vf_reset);
+        self.wrap_sprites = other.wrap_sprites.or(self.wrap_sprites);
+        self.display_wait = other.display_wait.or(self.display_wait);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.preset.is_none() && self.shift_uses_vy.is_none() && self.load_store_increments_i.is_none() &&
+            self.jump_uses_vx.is_none() && self.vf_reset.is_none() && self.wrap_sprites.is_none() &&
+            self.display_wait.is_none()
+    }
+
+    // Quirks to use instead of `base`, or None if nothing is configured
+    pub fn resolve(&self, base: Quirks) -> Option<Quirks> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut quirks = self.preset.as_ref().and_then(|p| Quirks::from_name(p)).unwrap_or(base);
+        quirks.shift_uses_vy = self.shift_uses_vy.unwrap_or(quirks.shift_uses_vy);
+        quirks.load_store_increments_i = self.load_store_increments_i.unwrap_or(quirks.load_store_increments_i);
+        quirks.jump_uses_vx = self.jump_uses_vx.unwrap_or(quirks.jump_uses_vx);
+        quirks.vf_reset = self.vf_reset.unwrap_or(quirks.vf_reset);
+        quirks.wrap_sprites = self.wrap_sprites.unwrap_or(quirks.wrap_sprites);
+        quirks.display_wait = self.display_wait.unwrap_or(quirks.display_wait);
+        Some(quirks)
+    }
+}
+
+impl Settings {
+    pub fn merge(&mut self, other: &Settings) {
+        self.video.merge(&other.video);
+        self.audio.merge(&other.audio);
+        self.input.merge(&other.input);
+        self.timing.merge(&other.timing);
+        self.quirks.merge(&other.quirks);
+    }
+}
+
+impl Config {
+    // $XDG_CONFIG_HOME/rs-chip8-emu/config.toml
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join(CONFIG_DIR).join(CONFIG_FILE))
+    }
+
+    // A missing file is an empty config, but a malformed one is reported
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut f) => {
+                f.read_to_string(&mut contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+            },
+            Err(_)    => return Ok(Config::default())
+        }
+
+        toml::from_str(&contents).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // Global settings with any overrides for the ROM applied, matching by
+    // file name and then by hash
+    pub fn settings_for(&self, rom_file: &str) -> Settings {
+        let mut settings = self.global.clone();
+        settings.merge(&self.rom_settings(rom_file));
+        settings
+    }
+
+    // Just the sections for the ROM, by file name then SHA-1
+    pub fn rom_settings(&self, rom_file: &str) -> Settings {
+        let mut settings = Settings::default();
+
+        let file_name = Path::new(rom_file).file_name().and_then(|name| name.to_str());
+        if let Some(rom) = file_name.and_then(|name| self.rom.get(name)) {
+            settings.merge(rom);
+        }
+
+        let mut bytes = Vec::new();
+        if File::open(rom_file).and_then(|mut f| f.read_to_end(&mut bytes)).is_ok() {
+            if let Some(rom) = self.rom.get(&sha1_hex(&bytes)) {
+                settings.merge(rom);
+            }
+        }
+
+        settings
+    }
+}
@@ -2,34 +2,104 @@
 extern crate sdl2;
 extern crate tui;
 extern crate clap;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate sha1;
+extern crate toml;
+extern crate dirs;
+extern crate termion;
+extern crate png;
+extern crate gif;
 
 use std::thread;
+use std::path::Path;
 use std::sync::mpsc::{channel, Receiver};
 use clap::{Arg, App};
 
 mod chip8;
 mod backends;
+mod config;
 
-use chip8::Chip8;
-use chip8::core::{RcRefKeyboardInterface, RcRefDisplayInterface};
+use chip8::{Chip8, DEFAULT_INSTRUCTIONS_PER_FRAME};
 use chip8::memory::Memory;
-use chip8::display_buffer::DisplayBuffer;
+use chip8::timing::TimingMode;
+use chip8::variant::MachineVariant;
+use chip8::quirks::Quirks;
+use chip8::romdb::RomDatabase;
+use chip8::recorder::RecordFormat;
+use config::{Config, Settings, QuirksConfig, KeyBinding};
+use chip8::vip::{VipMemory, VipSystem};
+use chip8::link::{link, CoreIO};
 
 enum Msg {
     Exit
 }
 
-fn simulation_thread(display: RcRefDisplayInterface, keyboard: RcRefKeyboardInterface, rx: Receiver<Msg>, file: String) {
-    let mut mem = Memory::new();
-    if mem.load_file(&file).is_err() {
-        println!("Unable to load ROM");
+fn vip_simulation_thread(io: CoreIO, rx: Receiver<Msg>, opts: Opts) {
+    let mut mem = VipMemory::new();
+    let interpreter = opts.vip_interpreter.clone().unwrap();
+    let monitor = opts.vip_monitor.as_ref().map(|s| s.as_str());
+    if mem.load_images(&interpreter, monitor, &opts.rom_file).is_err() {
+        eprintln!("Unable to load VIP interpreter or ROM");
         return;
     }
-    
-    let mut chip8 = Chip8::new(&mut mem, &display, &keyboard);
 
+    let (display, keyboard, _) = io.into_interfaces();
+    let mut vip = VipSystem::new(&mut mem, &display, &keyboard);
+    vip.set_real_time(opts.real_time);
+
+    loop {
+        vip.run_frame();
+        match rx.try_recv() {
+            Ok(Msg::Exit) => break,
+            _             => {}
+        }
+    }
+}
+
+fn simulation_thread(mut mem: Memory, io: CoreIO, rx: Receiver<Msg>, opts: Opts) {
+    // Command line options and the config sections for the ROM take
+    // precedence over the ROM database, which takes precedence over the
+    // global config
+    let (db_quirks, db_instructions_per_frame) = match mem.rom_info() {
+        Some(info) => (info.quirks, info.instructions_per_frame),
+        None       => (None, None)
+    };
+
+    // The core's interfaces belong to this thread alone
+    let (display, keyboard, audio) = io.into_interfaces();
+    let variant = mem.variant();
+    let mut quirks = variant.quirks();
+    quirks = opts.global_quirks.resolve(quirks).unwrap_or(quirks);
+    quirks = db_quirks.unwrap_or(quirks);
+    quirks = opts.rom_quirks.resolve(quirks).unwrap_or(quirks);
+    let mut chip8 = Chip8::new(&mut mem, &display, &keyboard, &audio, variant);
+    chip8.set_quirks(opts.quirks.unwrap_or(quirks));
+    chip8.set_instructions_per_frame(opts.instructions_per_frame
+                                     .or(db_instructions_per_frame)
+                                     .or(opts.default_instructions_per_frame)
+                                     .unwrap_or(DEFAULT_INSTRUCTIONS_PER_FRAME));
+    chip8.set_real_time(opts.real_time);
+    if let Some(frame_rate) = opts.frame_rate {
+        chip8.set_frame_rate(frame_rate);
+    }
+    chip8.set_timing_mode(opts.timing_mode);
+    chip8.set_vip_hybrid(opts.vip_hybrid);
+
+    let mut frame_count = chip8.frame_count();
     loop {
         chip8.cycle();
+
+        if opts.frame_stats && chip8.frame_count() != frame_count {
+            frame_count = chip8.frame_count();
+            if let Some(stats) = chip8.last_frame_stats() {
+                eprintln!("Frame {}: {} instructions, {}/{} cycles, {} idle",
+                          frame_count, stats.instructions, stats.cycles_used, stats.budget, stats.cycles_idle);
+            }
+        }
+
         match rx.try_recv() {
             Ok(Msg::Exit) => break,
             _             => {}
@@ -39,7 +109,25 @@ fn simulation_thread(display: RcRefDisplayInterface, keyboard: RcRefKeyboardInte
 
 struct Opts {
     backend: backends::BackendType,
-    rom_file: String
+    rom_file: String,
+    variant: Option<MachineVariant>,
+    quirks: Option<Quirks>,
+    // Quirks from the config sections for the ROM, then the global ones
+    rom_quirks: QuirksConfig,
+    global_quirks: QuirksConfig,
+    use_romdb: bool,
+    instructions_per_frame: Option<usize>,
+    default_instructions_per_frame: Option<usize>,
+    real_time: bool,
+    frame_rate: Option<u32>,
+    timing_mode: TimingMode,
+    frame_stats: bool,
+    vip_hybrid: bool,
+    vip_interpreter: Option<String>,
+    vip_monitor: Option<String>,
+    record: Option<String>,
+    // Config file settings for the ROM, for what has no command line option
+    settings: Settings
 }
 
 fn parse_args() -> Opts {
@@ -56,33 +144,242 @@ fn parse_args() -> Opts {
              .possible_value("sdl")
              .possible_value("text")
              .default_value("sdl"))
+        .arg(Arg::with_name("variant")
+             .long("variant")
+             .value_name("VARIANT")
+             .help("Select the machine variant instead of detecting it from the ROM")
+             .takes_value(true)
+             .possible_value("chip8")
+             .possible_value("chip48")
+             .possible_value("schip1.0")
+             .possible_value("schip1.1")
+             .possible_value("xochip")
+             .possible_value("hires")
+             .possible_value("chip8x")
+             .possible_value("megachip"))
+        .arg(Arg::with_name("quirks")
+             .long("quirks")
+             .value_name("PRESET")
+             .help("Use the quirks of an interpreter instead of those for the variant or ROM")
+             .takes_value(true)
+             .possible_value("chip8")
+             .possible_value("vip")
+             .possible_value("schip")
+             .possible_value("xochip"))
+        .arg(Arg::with_name("no-romdb")
+             .long("no-romdb")
+             .help("Ignore the ROM database entry for the program"))
+        .arg(Arg::with_name("ipf")
+             .long("ipf")
+             .value_name("COUNT")
+             .help("Number of instructions executed per 60Hz frame")
+             .takes_value(true))
+        .arg(Arg::with_name("timing")
+             .long("timing")
+             .value_name("MODEL")
+             .help("Select the instruction timing model")
+             .takes_value(true)
+             .possible_value("instructions")
+             .possible_value("vip"))
+        .arg(Arg::with_name("frame-stats")
+             .long("frame-stats")
+             .help("Report cycle usage for each frame when using VIP timing"))
+        .arg(Arg::with_name("vip-hybrid")
+             .long("vip-hybrid")
+             .help("Execute 0NNN calls as RCA 1802 machine code"))
+        .arg(Arg::with_name("vip-interpreter")
+             .long("vip-interpreter")
+             .value_name("FILE")
+             .help("Emulate a COSMAC VIP running this CHIP-8 interpreter image")
+             .takes_value(true))
+        .arg(Arg::with_name("vip-monitor")
+             .long("vip-monitor")
+             .value_name("FILE")
+             .help("COSMAC VIP monitor ROM image, used with --vip-interpreter")
+             .takes_value(true)
+             .requires("vip-interpreter"))
+        .arg(Arg::with_name("unthrottled")
+             .long("unthrottled")
+             .help("Run frames as fast as possible instead of in real time"))
+        .arg(Arg::with_name("keymap")
+             .long("keymap")
+             .value_name("PRESET")
+             .help("Select the host keys for the keypad")
+             .takes_value(true)
+             .possible_value("cosmac")
+             .possible_value("azerty")
+             .possible_value("qwertz")
+             .possible_value("hex"))
+        .arg(Arg::with_name("key")
+             .long("key")
+             .value_name("K=NAME[,NAME...]")
+             .help("Bind host keys to CHIP-8 key K, given as a hex digit")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1))
+        .arg(Arg::with_name("keypad")
+             .long("keypad")
+             .help("Show a clickable keypad beside the display"))
+        .arg(Arg::with_name("fullscreen")
+             .long("fullscreen")
+             .help("Start in fullscreen mode"))
+        .arg(Arg::with_name("scaling")
+             .long("scaling")
+             .value_name("MODE")
+             .help("Scale the display by whole multiples or to fill the window")
+             .takes_value(true)
+             .possible_value("integer")
+             .possible_value("fractional"))
+        .arg(Arg::with_name("text-mode")
+             .long("text-mode")
+             .value_name("MODE")
+             .help("Draw the text UI with half blocks, quadrants, sextants or Braille, or the finest that fits")
+             .takes_value(true)
+             .possible_value("auto")
+             .possible_value("half")
+             .possible_value("quadrant")
+             .possible_value("sextant")
+             .possible_value("braille"))
+        .arg(Arg::with_name("record")
+             .long("record")
+             .value_name("FILE")
+             .help("Record the display to a .gif, .y4m or .ppm file, or - for Y4M on standard output")
+             .takes_value(true))
+        .arg(Arg::with_name("config")
+             .long("config")
+             .value_name("FILE")
+             .help("Read settings from this file instead of the user config file")
+             .takes_value(true))
         .arg(Arg::with_name("INPUT")
              .help("Sets the input file to use")
              .required(true)
              .index(1)
              .default_value("programs/games/TICTAC"))
         .get_matches();
+
+    // Command line options take precedence over the config file
+    let config_path = matches.value_of("config").map(|path| path.into()).or_else(Config::default_path);
+    let config = match config_path {
+        Some(path) => Config::load(&path).unwrap_or_else(|e| {
+            eprintln!("Unable to read config: {}", e);
+            Config::default()
+        }),
+        None       => Config::default()
+    };
+    let rom_file = matches.value_of("INPUT").unwrap();
+    let mut settings = config.settings_for(rom_file);
+    let rom_settings = config.rom_settings(rom_file);
+
+    if matches.is_present("keypad") {
+        settings.video.keypad = Some(true);
+    }
+    if matches.is_present("fullscreen") {
+        settings.video.fullscreen = Some(true);
+    }
+    if let Some(scaling) = matches.value_of("scaling") {
+        settings.video.scaling = Some(scaling.into());
+    }
+    if let Some(mode) = matches.value_of("text-mode") {
+        settings.video.text_mode = Some(mode.into());
+    }
+    if let Some(preset) = matches.value_of("keymap") {
+        settings.input.preset = Some(preset.into());
+        settings.input.keys.clear();
+    }
+    for spec in matches.values_of("key").into_iter().flat_map(|specs| specs) {
+        let mut parts = spec.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(names)) => {
+                let names = names.split(',').map(|name| name.trim().to_string()).collect();
+                settings.input.keys.insert(key.trim().into(), KeyBinding::Many(names));
+            },
+            _                        => eprintln!("Invalid key binding {}", spec)
+        }
+    }
+
     Opts {
         backend: if matches.value_of("backend").expect("Unknown backend") == "sdl" {
             backends::BackendType::SDL
         } else {
             backends::BackendType::TUI
         },
-        rom_file: matches.value_of("INPUT").unwrap().into()
+        rom_file: rom_file.into(),
+        variant: matches.value_of("variant").and_then(MachineVariant::from_name),
+        quirks: matches.value_of("quirks").and_then(Quirks::from_name),
+        rom_quirks: rom_settings.quirks,
+        global_quirks: config.global.quirks.clone(),
+        use_romdb: !matches.is_present("no-romdb"),
+        instructions_per_frame: matches.value_of("ipf")
+            .map(|ipf| ipf.parse().expect("Invalid instructions per frame"))
+            .or(rom_settings.timing.instructions_per_frame),
+        default_instructions_per_frame: config.global.timing.instructions_per_frame,
+        real_time: !matches.is_present("unthrottled") && settings.timing.real_time.unwrap_or(true),
+        frame_rate: settings.timing.frame_rate,
+        timing_mode: match matches.value_of("timing").or(settings.timing.model.as_ref().map(|m| m.as_str())) {
+            Some("vip") => TimingMode::Vip,
+            _           => TimingMode::InstructionCount
+        },
+        frame_stats: matches.is_present("frame-stats"),
+        vip_hybrid: matches.is_present("vip-hybrid"),
+        vip_interpreter: matches.value_of("vip-interpreter").map(|s| s.into()),
+        vip_monitor: matches.value_of("vip-monitor").map(|s| s.into()),
+        record: matches.value_of("record").map(|s| s.into()),
+        settings: settings
     }
 }
 
 fn main() {
     let opts = parse_args();
-    let mut ref_backend = backends::get_backend(opts.backend);
-    let mut backend = ref_backend.as_mut();
-    
-    let keyboard: RcRefKeyboardInterface = backend.get_keyboard_interface();
-    let display: RcRefDisplayInterface = backend.get_display_interface();
-    
+
+    // The text UI draws to standard output, so a stream can't go there too
+    if let backends::BackendType::TUI = opts.backend {
+        if opts.record.as_ref().map_or(false, |path| path == "-") {
+            eprintln!("Recording to standard output needs the SDL backend");
+            return;
+        }
+    }
+
+    let (core_io, frontend_io) = link();
+    let mut ref_backend = backends::get_backend(opts.backend, &opts.settings, frontend_io);
+    let backend = ref_backend.as_mut();
+
+    let file_name = Path::new(&opts.rom_file).file_name().map_or(opts.rom_file.clone(), |name| name.to_string_lossy().into());
+    backend.set_title(&format!("{} - rs-chip8-emu", file_name));
+
+    if let Some(ref path) = opts.record {
+        let path = Path::new(path);
+        let format = RecordFormat::from_path(path).unwrap_or(if path == Path::new("-") {
+            RecordFormat::Y4m
+        } else {
+            backends::record_format(&opts.settings)
+        });
+        backend.start_recording(path, format);
+    }
+
     let (tx, rx) = channel();
 
-    let sim_thread = thread::spawn(move || { simulation_thread(display, keyboard, rx, opts.rom_file); });
+    let sim_thread = if opts.vip_interpreter.is_some() {
+        thread::spawn(move || { vip_simulation_thread(core_io, rx, opts); })
+    } else {
+        let romdb = if opts.use_romdb { Some(RomDatabase::bundled()) } else { None };
+        let mut mem = Memory::new();
+        if mem.load_file(&opts.rom_file, opts.variant, romdb.as_ref()).is_err() {
+            eprintln!("Unable to load ROM");
+            return;
+        }
+
+        if let Some(info) = mem.rom_info() {
+            eprintln!("{} by {}", info.title, if info.authors.is_empty() { "unknown".into() } else { info.authors.join(", ") });
+            backend.set_title(&format!("{} - rs-chip8-emu", info.title));
+            let video = &opts.settings.video;
+            if video.palette.is_none() && video.foreground.is_none() && video.background.is_none() {
+                backend.set_palette(&info.palette);
+            }
+            backend.set_game_keys(&info.keys);
+        }
+
+        thread::spawn(move || { simulation_thread(mem, core_io, rx, opts); })
+    };
 
     backend.run();
 